@@ -0,0 +1,345 @@
+// Server-side computer players
+use crate::game::{EndState, Mode, State};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// How a computer player picks its moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Strategy {
+    /// Any legal move.
+    Random,
+    /// Minimax with alpha-beta pruning. Plays perfectly on 3x3 boards, larger
+    /// boards are only searched a few moves ahead.
+    Perfect,
+    /// Chance (0-100%) of playing like `Perfect` on any given move, otherwise
+    /// the bot blunders with a random move.
+    Difficulty(u8),
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    /// Parses "random", "perfect" or a difficulty from 0 to 100.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "random" => Ok(Strategy::Random),
+            "perfect" => Ok(Strategy::Perfect),
+            other => match other.parse::<u8>() {
+                Ok(difficulty) if difficulty <= 100 => Ok(Strategy::Difficulty(difficulty)),
+                _ => Err(format!("Unknown bot strategy: {}", s)),
+            },
+        }
+    }
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::Random => write!(f, "random"),
+            Strategy::Perfect => write!(f, "perfect"),
+            Strategy::Difficulty(difficulty) => write!(f, "difficulty {}", difficulty),
+        }
+    }
+}
+
+/// Pick a move for the side whose turn it is, or None if the game is over.
+/// Can be slow on big boards, so call it from a blocking task.
+pub fn choose_move(state: &State, strategy: Strategy) -> Option<usize> {
+    let moves = state.legal_moves();
+    if moves.is_empty() {
+        return None;
+    }
+
+    let mut rng = rand::thread_rng();
+    let blunder = match strategy {
+        Strategy::Random => true,
+        Strategy::Perfect => false,
+        Strategy::Difficulty(difficulty) => rng.gen_range(0..100) >= difficulty,
+    };
+    if blunder {
+        return moves.choose(&mut rng).copied();
+    }
+
+    best_move(state)
+}
+
+const WIN_SCORE: i64 = 1_000_000_000;
+
+fn best_move(state: &State) -> Option<usize> {
+    // only the board matters to the search, so don't drag the chat log along
+    let mut position = state.clone();
+    position.players.clear();
+    position.chat.clear();
+    position.moves.clear();
+
+    // shuffle so that equally good moves aren't always played in board order
+    let mut moves = candidate_moves(&position);
+    moves.shuffle(&mut rand::thread_rng());
+
+    let depth = match position.mode {
+        Mode::Standard => search_depth(position.board.iter().filter(|&&c| c == ' ').count()),
+        // ultimate games branch less when sent to a single sub-board
+        Mode::Ultimate if moves.len() <= 9 => 6,
+        Mode::Ultimate => 3,
+    };
+
+    let mut best = None;
+    let mut alpha = -i64::MAX;
+    for space in moves {
+        let mut child = position.clone();
+        if child.play(space).is_err() {
+            continue;
+        }
+        let score = -negamax(&child, depth - 1, -i64::MAX, -alpha);
+        if best.is_none() || score > alpha {
+            alpha = score;
+            best = Some(space);
+        }
+    }
+    best
+}
+
+/// Search the whole game tree once it is small enough, otherwise look a fixed
+/// number of moves ahead and fall back to `evaluate`.
+fn search_depth(empty: usize) -> usize {
+    match empty {
+        0..=10 => empty.max(1),
+        11..=16 => 4,
+        17..=25 => 3,
+        _ => 2,
+    }
+}
+
+/// Score of the position for the side to move.
+fn negamax(position: &State, depth: usize, mut alpha: i64, beta: i64) -> i64 {
+    match position.winner {
+        // the previous move won, so the side to move has lost. Prefer wins
+        // that come sooner by scoring them higher with more depth remaining.
+        Some(EndState::Win(_)) => return -(WIN_SCORE + depth as i64),
+        Some(EndState::Draw) => return 0,
+        None => {}
+    }
+    if depth == 0 {
+        return evaluate(position);
+    }
+
+    let mut best = -i64::MAX;
+    for space in candidate_moves(position) {
+        let mut child = position.clone();
+        if child.play(space).is_err() {
+            continue;
+        }
+        let score = -negamax(&child, depth - 1, -beta, -alpha);
+        best = best.max(score);
+        alpha = alpha.max(score);
+        if alpha >= beta {
+            break;
+        }
+    }
+    best
+}
+
+/// On boards bigger than 3x3 only consider spaces next to existing marks,
+/// which keeps the branching factor of a 15x15 board manageable.
+fn candidate_moves(position: &State) -> Vec<usize> {
+    let moves = position.legal_moves();
+    let size = position.board_size;
+    if size <= 3 || position.mode == Mode::Ultimate {
+        return moves;
+    }
+
+    if position.board.iter().all(|&c| c == ' ') {
+        return vec![(size / 2) * size + size / 2];
+    }
+
+    let near_mark = |space: usize| {
+        let (row, col) = ((space / size) as isize, (space % size) as isize);
+        (-1..=1).any(|dr| {
+            (-1..=1).any(|dc| {
+                let (r, c) = (row + dr, col + dc);
+                r >= 0
+                    && c >= 0
+                    && (r as usize) < size
+                    && (c as usize) < size
+                    && position.board[r as usize * size + c as usize] != ' '
+            })
+        })
+    };
+
+    let nearby: Vec<usize> = moves.iter().copied().filter(|&s| near_mark(s)).collect();
+    if nearby.is_empty() {
+        moves
+    } else {
+        nearby
+    }
+}
+
+/// Heuristic score for the side to move. In ultimate games, winning
+/// sub-boards is worth far more than progress within them.
+fn evaluate(position: &State) -> i64 {
+    let me = position.turn;
+    match &position.ultimate {
+        None => score_lines(
+            &position.board,
+            position.board_size,
+            position.win_length,
+            me,
+        ),
+        Some(ultimate) => {
+            let meta: Vec<char> = ultimate
+                .sub_boards
+                .iter()
+                .map(|result| match result {
+                    Some(EndState::Win(team)) => *team,
+                    Some(EndState::Draw) => '#',
+                    None => ' ',
+                })
+                .collect();
+
+            let mut score = 1000 * score_lines(&meta, 3, 3, me);
+            for (sub_board, result) in ultimate.sub_boards.iter().enumerate() {
+                if result.is_none() {
+                    score +=
+                        score_lines(&position.board[sub_board * 9..sub_board * 9 + 9], 3, 3, me);
+                }
+            }
+            score
+        }
+    }
+}
+
+/// Every line of `length` spaces that only one side has marks in is worth
+/// more the fuller it is. Any other character blocks a line for both sides.
+/// Long win lengths would overflow, so line scores stop growing at
+/// `MAX_LINE_SCORE` and the total always stays below `WIN_SCORE`.
+fn score_lines(cells: &[char], size: usize, length: usize, me: char) -> i64 {
+    let size = size as isize;
+    let length = length as isize;
+    let them = if me == 'X' { 'O' } else { 'X' };
+
+    let mut score: i64 = 0;
+    for row in 0..size {
+        for col in 0..size {
+            for (dr, dc) in [(0, 1), (1, 0), (1, 1), (1, -1)] {
+                let end_row = row + dr * (length - 1);
+                let end_col = col + dc * (length - 1);
+                if end_row < 0 || end_row >= size || end_col < 0 || end_col >= size {
+                    continue;
+                }
+
+                let (mut mine, mut theirs, mut blocked) = (0, 0, false);
+                for i in 0..length {
+                    match cells[((row + dr * i) * size + col + dc * i) as usize] {
+                        ' ' => {}
+                        c if c == me => mine += 1,
+                        c if c == them => theirs += 1,
+                        _ => blocked = true,
+                    }
+                }
+
+                if blocked {
+                    continue;
+                }
+                if theirs == 0 && mine > 0 {
+                    score = score.saturating_add(line_score(mine));
+                } else if mine == 0 && theirs > 0 {
+                    score = score.saturating_sub(line_score(theirs));
+                }
+            }
+        }
+    }
+    score.clamp(-(WIN_SCORE - 1), WIN_SCORE - 1)
+}
+
+const MAX_LINE_SCORE: i64 = WIN_SCORE / 10;
+
+fn line_score(marks: u32) -> i64 {
+    10_i64.saturating_pow(marks).min(MAX_LINE_SCORE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{Options, MAX_BOARD_SIZE};
+
+    fn position(board_size: usize, win_length: usize, moves: &[usize]) -> State {
+        let mut state = State::new(Options {
+            board_size,
+            win_length,
+            ..Options::default()
+        });
+        for &space in moves {
+            state.play(space).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn takes_a_win() {
+        // X: 0 1, O: 3 4
+        let state = position(3, 3, &[0, 3, 1, 4]);
+        assert_eq!(choose_move(&state, Strategy::Perfect), Some(2));
+    }
+
+    #[test]
+    fn blocks_a_win() {
+        // X: 0 1, O: 4
+        let state = position(3, 3, &[0, 4, 1]);
+        assert_eq!(choose_move(&state, Strategy::Perfect), Some(2));
+    }
+
+    /// X is one short of filling the top row and is building another on row
+    /// 6, O has rows 2 and 4. Uncapped, these lines score more than a win.
+    fn long_lines() -> Vec<usize> {
+        let size = MAX_BOARD_SIZE;
+        let mut moves = Vec::new();
+        for col in 0..size - 2 {
+            moves.extend([col, 2 * size + col, 6 * size + col, 4 * size + col]);
+        }
+        moves.push(size - 2);
+        moves
+    }
+
+    #[test]
+    fn blocks_a_win_on_long_lines() {
+        let state = position(MAX_BOARD_SIZE, MAX_BOARD_SIZE, &long_lines());
+        assert_eq!(state.turn, 'O');
+        assert_eq!(
+            choose_move(&state, Strategy::Perfect),
+            Some(MAX_BOARD_SIZE - 1)
+        );
+    }
+
+    #[test]
+    fn takes_a_win_on_long_lines() {
+        let mut moves = long_lines();
+        moves.push(10 * MAX_BOARD_SIZE);
+        let state = position(MAX_BOARD_SIZE, MAX_BOARD_SIZE, &moves);
+        assert_eq!(state.turn, 'X');
+        assert_eq!(
+            choose_move(&state, Strategy::Perfect),
+            Some(MAX_BOARD_SIZE - 1)
+        );
+    }
+
+    #[test]
+    fn line_scores_stay_below_a_win() {
+        let mut board = vec!['X'; MAX_BOARD_SIZE * MAX_BOARD_SIZE];
+        board[0] = ' ';
+        let score = score_lines(&board, MAX_BOARD_SIZE, MAX_BOARD_SIZE, 'X');
+        assert!(score > 0 && score < WIN_SCORE);
+        let score = score_lines(&board, MAX_BOARD_SIZE, MAX_BOARD_SIZE, 'O');
+        assert!(score < 0 && score > -WIN_SCORE);
+    }
+
+    #[test]
+    fn strategies_parse() {
+        assert_eq!("Perfect".parse(), Ok(Strategy::Perfect));
+        assert_eq!("random".parse(), Ok(Strategy::Random));
+        assert_eq!("75".parse(), Ok(Strategy::Difficulty(75)));
+        assert!("101".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,354 @@
+// Filtering chat messages and names for rude words, links and spam
+use crate::game::Error;
+use serde::{Deserialize, Serialize};
+
+/// How strictly a game's chat is filtered, chosen when it is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    /// Messages are only trimmed and length-checked.
+    Off,
+    /// Listed words are masked and links removed.
+    #[default]
+    Standard,
+    /// Messages with listed words or links are refused.
+    Strict,
+}
+
+/// What to do with a message or name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Post it, possibly changed.
+    Allow(String),
+    /// Refuse it, telling the sender why.
+    Reject(Error),
+    /// Quietly throw it away, so the sender isn't prompted to get around the
+    /// filter.
+    Drop,
+}
+
+/// One step of a `Filter`. Each rule sees the text as the rules before it
+/// left it.
+pub trait Rule: std::fmt::Debug + Send + Sync {
+    fn apply(&self, text: String, strictness: Strictness) -> Verdict;
+}
+
+/// Rules run in order on every chat message and name change, stopping at
+/// the first that doesn't allow it.
+#[derive(Debug)]
+pub struct Filter {
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::standard(WordList::default())
+    }
+}
+
+impl Filter {
+    pub fn new(rules: Vec<Box<dyn Rule>>) -> Filter {
+        Filter { rules }
+    }
+
+    /// Collapse repeated characters, then deal with links, then the words.
+    pub fn standard(words: WordList) -> Filter {
+        Filter::new(vec![
+            Box::new(CollapseRepeats { max: 3 }),
+            Box::new(Links),
+            Box::new(words),
+        ])
+    }
+
+    /// The standard filter, with the word list in the file at `path`, see
+    /// `WordList::parse`.
+    pub fn load(path: &str) -> Result<Filter, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Filter::standard(WordList::parse(&text)))
+    }
+
+    pub fn check(&self, text: &str, strictness: Strictness) -> Verdict {
+        let mut text = text.to_string();
+        if strictness == Strictness::Off {
+            return Verdict::Allow(text);
+        }
+        for rule in &self.rules {
+            match rule.apply(text, strictness) {
+                Verdict::Allow(changed) => text = changed,
+                verdict => return verdict,
+            }
+        }
+        Verdict::Allow(text)
+    }
+}
+
+/// Shortens runs of the same character, like "sooooooo", to `max`.
+#[derive(Debug)]
+pub struct CollapseRepeats {
+    pub max: usize,
+}
+
+impl Rule for CollapseRepeats {
+    fn apply(&self, text: String, _: Strictness) -> Verdict {
+        let mut collapsed = String::with_capacity(text.len());
+        let mut last = None;
+        let mut run = 0;
+        for c in text.chars() {
+            if Some(c) == last {
+                run += 1;
+            } else {
+                last = Some(c);
+                run = 1;
+            }
+            if run <= self.max {
+                collapsed.push(c);
+            }
+        }
+        Verdict::Allow(collapsed)
+    }
+}
+
+/// Removes links in standard games and refuses them in strict ones.
+#[derive(Debug)]
+pub struct Links;
+
+/// Endings of bare domain names, like "example.com", that are treated as
+/// links. Anything with a scheme or starting with "www." is one regardless.
+const LINK_TLDS: &[&str] = &[
+    "com", "net", "org", "edu", "gov", "io", "co", "gg", "ly", "me", "tv", "xyz", "info", "app",
+    "dev", "uk", "us", "ca", "de", "ru", "be", "to",
+];
+
+fn is_link(token: &str) -> bool {
+    let token = token
+        .trim_end_matches(|c: char| ".,!?;:)]}\"'".contains(c))
+        .to_lowercase();
+    if token.contains("://") || token.starts_with("www.") {
+        return true;
+    }
+    let host = token.split('/').next().unwrap_or_default();
+    let labels: Vec<&str> = host.split('.').collect();
+    labels.len() >= 2
+        && labels.iter().all(|label| !label.is_empty())
+        && LINK_TLDS.contains(labels.last().unwrap())
+}
+
+impl Rule for Links {
+    fn apply(&self, text: String, strictness: Strictness) -> Verdict {
+        let links: Vec<_> = runs(&text, |c| !c.is_whitespace())
+            .into_iter()
+            .filter(|&(start, end)| is_link(&text[start..end]))
+            .collect();
+        if links.is_empty() {
+            return Verdict::Allow(text);
+        }
+        if strictness == Strictness::Strict {
+            return Verdict::Reject(Error::LinksNotAllowed);
+        }
+        Verdict::Allow(replace(&text, &links, |_| "[link removed]".to_string()))
+    }
+}
+
+/// Words to keep out of the chat. Matching ignores case, punctuation around
+/// the word and extra repeated letters, so "Fuuuck!" matches "fuck" but "as"
+/// doesn't match "ass". Only whole words are matched, so "class" doesn't
+/// match "ass" either.
+#[derive(Debug, Default)]
+pub struct WordList {
+    /// Masked in standard games and refused in strict ones.
+    words: Vec<Spelling>,
+    /// Messages containing these are dropped in any filtered game.
+    dropped: Vec<Spelling>,
+}
+
+impl WordList {
+    /// One word per line. Blank lines and lines starting with `#` are
+    /// ignored, and words starting with `!` drop the whole message instead
+    /// of being masked.
+    pub fn parse(text: &str) -> WordList {
+        let mut list = WordList::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (list, word) = match line.strip_prefix('!') {
+                Some(word) => (&mut list.dropped, word),
+                None => (&mut list.words, line),
+            };
+            let word = spelling(word);
+            if !word.is_empty() {
+                list.push(word);
+            }
+        }
+        list
+    }
+}
+
+/// A word in lowercase without punctuation, as runs of the same letter and
+/// how long each run is.
+type Spelling = Vec<(char, usize)>;
+
+fn spelling(word: &str) -> Spelling {
+    let mut spelling: Spelling = Vec::with_capacity(word.len());
+    for c in word.chars().filter(|c| c.is_alphanumeric()) {
+        for lower in c.to_lowercase() {
+            match spelling.last_mut() {
+                Some((last, run)) if *last == lower => *run += 1,
+                _ => spelling.push((lower, 1)),
+            }
+        }
+    }
+    spelling
+}
+
+/// Whether `text` is `word` with some of its letters repeated more times.
+fn spelled_like(text: &Spelling, word: &Spelling) -> bool {
+    text.len() == word.len()
+        && text
+            .iter()
+            .zip(word)
+            .all(|(&(a, n), &(b, m))| a == b && n >= m)
+}
+
+impl Rule for WordList {
+    fn apply(&self, text: String, strictness: Strictness) -> Verdict {
+        let mut matched = Vec::new();
+        for (start, end) in runs(&text, char::is_alphanumeric) {
+            let word = spelling(&text[start..end]);
+            let listed = |list: &[Spelling]| list.iter().any(|w| spelled_like(&word, w));
+            if listed(&self.dropped) {
+                return Verdict::Drop;
+            }
+            if listed(&self.words) {
+                matched.push((start, end));
+            }
+        }
+        if matched.is_empty() {
+            return Verdict::Allow(text);
+        }
+        if strictness == Strictness::Strict {
+            return Verdict::Reject(Error::Profanity);
+        }
+        Verdict::Allow(replace(&text, &matched, |word| {
+            "*".repeat(word.chars().count())
+        }))
+    }
+}
+
+/// Byte ranges of the runs of characters in `text` for which `part` is true.
+fn runs(text: &str, part: impl Fn(char) -> bool) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (part(c), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                ranges.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        ranges.push((s, text.len()));
+    }
+    ranges
+}
+
+/// Replace each of the ranges, which must be in order and not overlap.
+fn replace(text: &str, ranges: &[(usize, usize)], with: impl Fn(&str) -> String) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut copied = 0;
+    for &(start, end) in ranges {
+        replaced.push_str(&text[copied..start]);
+        replaced.push_str(&with(&text[start..end]));
+        copied = end;
+    }
+    replaced.push_str(&text[copied..]);
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(words: &str) -> Filter {
+        Filter::standard(WordList::parse(words))
+    }
+
+    #[test]
+    fn listed_words_are_masked_or_refused() {
+        let filter = filter("fuck\nass");
+        assert_eq!(
+            filter.check("Fuuuck! what", Strictness::Standard),
+            Verdict::Allow("******! what".to_string())
+        );
+        assert_eq!(
+            filter.check("what an ass", Strictness::Strict),
+            Verdict::Reject(Error::Profanity)
+        );
+        assert_eq!(
+            filter.check("what an ass", Strictness::Off),
+            Verdict::Allow("what an ass".to_string())
+        );
+    }
+
+    #[test]
+    fn words_with_fewer_repeats_than_listed_are_allowed() {
+        let filter = filter("boob\nass");
+        for text in ["Bob", "as good as it gets", "class"] {
+            assert_eq!(
+                filter.check(text, Strictness::Strict),
+                Verdict::Allow(text.to_string())
+            );
+        }
+        assert_eq!(
+            filter.check("booooob", Strictness::Strict),
+            Verdict::Reject(Error::Profanity)
+        );
+    }
+
+    #[test]
+    fn dropped_words_drop_the_message() {
+        let filter = filter("# comment\n!spam\n");
+        assert_eq!(
+            filter.check("buy SPAM now", Strictness::Standard),
+            Verdict::Drop
+        );
+    }
+
+    #[test]
+    fn links_are_removed_or_refused() {
+        let filter = Filter::default();
+        assert_eq!(
+            filter.check(
+                "see https://example.com/x, or example.org.",
+                Strictness::Standard
+            ),
+            Verdict::Allow("see [link removed] or [link removed]".to_string())
+        );
+        assert_eq!(
+            filter.check("go to www.example", Strictness::Strict),
+            Verdict::Reject(Error::LinksNotAllowed)
+        );
+        for text in ["e.g. this", "version 1.2", "wait..."] {
+            assert_eq!(
+                filter.check(text, Strictness::Strict),
+                Verdict::Allow(text.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn long_runs_are_collapsed() {
+        let rule = CollapseRepeats { max: 3 };
+        assert_eq!(
+            rule.apply("sooooo gooood!!!!!".to_string(), Strictness::Standard),
+            Verdict::Allow("sooo goood!!!".to_string())
+        );
+        assert_eq!(
+            rule.apply("book".to_string(), Strictness::Standard),
+            Verdict::Allow("book".to_string())
+        );
+    }
+}
@@ -0,0 +1,158 @@
+// How messages to and from a game's client are encoded
+use crate::game::{self, FromBrowser, ToBrowser};
+use serde::Deserialize;
+use serde_json::error::Category;
+
+/// Chosen with the `encoding` parameter of `/ws` and `/matchmake`. JSON is
+/// sent in text frames, and the others in binary frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// MessagePack, with structs as maps keyed by field name like in JSON.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+/// A message ready to be sent.
+pub enum Encoded {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Encoding {
+    pub fn is_binary(self) -> bool {
+        self != Encoding::Json
+    }
+
+    pub fn encode(self, msg: &ToBrowser) -> Result<Encoded, String> {
+        match self {
+            Encoding::Json => serde_json::to_string(msg)
+                .map(Encoded::Text)
+                .map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(msg)
+                .map(Encoded::Binary)
+                .map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(msg, &mut buffer).map_err(|e| e.to_string())?;
+                Ok(Encoded::Binary(buffer))
+            }
+        }
+    }
+
+    /// Decode a message from the client, or say what's wrong with it.
+    pub fn decode(self, data: &[u8]) -> Result<FromBrowser, (game::Error, String)> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| {
+                let code = match e.classify() {
+                    Category::Data => game::Error::InvalidMessage,
+                    Category::Syntax | Category::Eof | Category::Io => game::Error::InvalidJson,
+                };
+                (code, e.to_string())
+            }),
+            Encoding::MessagePack => rmp_serde::from_slice(data).map_err(|e| {
+                use rmp_serde::decode::Error;
+                let code = match e {
+                    // well-formed, but not a message we know
+                    Error::Syntax(_)
+                    | Error::TypeMismatch(_)
+                    | Error::LengthMismatch(_)
+                    | Error::OutOfRange => game::Error::InvalidMessage,
+                    _ => game::Error::InvalidEncoding,
+                };
+                (code, e.to_string())
+            }),
+            Encoding::Cbor => ciborium::from_reader(data).map_err(|e| {
+                let code = match e {
+                    ciborium::de::Error::Semantic(..) => game::Error::InvalidMessage,
+                    _ => game::Error::InvalidEncoding,
+                };
+                (code, e.to_string())
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+    use serde_json::{json, Value};
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    /// What a client of the encoding would send.
+    fn client_encode(encoding: Encoding, msg: &impl Serialize) -> Vec<u8> {
+        match encoding {
+            Encoding::Json => serde_json::to_vec(msg).unwrap(),
+            Encoding::MessagePack => rmp_serde::to_vec_named(msg).unwrap(),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(msg, &mut buffer).unwrap();
+                buffer
+            }
+        }
+    }
+
+    /// What a client of the encoding would read.
+    fn client_decode(encoding: Encoding, encoded: Encoded) -> Value {
+        match (encoding, encoded) {
+            (Encoding::Json, Encoded::Text(text)) => serde_json::from_str(&text).unwrap(),
+            (Encoding::MessagePack, Encoded::Binary(data)) => rmp_serde::from_slice(&data).unwrap(),
+            (Encoding::Cbor, Encoded::Binary(data)) => {
+                ciborium::from_reader(data.as_slice()).unwrap()
+            }
+            _ => panic!("{:?} sent in the wrong kind of frame", encoding),
+        }
+    }
+
+    #[test]
+    fn messages_to_the_browser_decode_the_same_in_every_encoding() {
+        let (mut game, _rx) = game::Game::new("test".to_string(), game::Options::default());
+        let x = game.add_player("X".to_string()).unwrap();
+        game.add_player("O".to_string()).unwrap();
+        game.take_turn(x.id, 4).unwrap();
+        let msg = ToBrowser::GameState(game.state.clone());
+        let expected = serde_json::to_value(&msg).unwrap();
+
+        for encoding in ENCODINGS {
+            let encoded = encoding.encode(&msg).unwrap();
+            assert_eq!(client_decode(encoding, encoded), expected, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn messages_from_the_browser_decode_in_every_encoding() {
+        for encoding in ENCODINGS {
+            let data = client_encode(encoding, &json!({ "Move": { "space": 4 } }));
+            assert!(
+                matches!(encoding.decode(&data), Ok(FromBrowser::Move { space: 4 })),
+                "{:?}",
+                encoding
+            );
+
+            let data = client_encode(encoding, &json!("Resign"));
+            assert!(matches!(encoding.decode(&data), Ok(FromBrowser::Resign)));
+        }
+    }
+
+    #[test]
+    fn unknown_messages_and_garbage_are_told_apart() {
+        for encoding in ENCODINGS {
+            let data = client_encode(encoding, &json!({ "Fly": { "to": "moon" } }));
+            let (code, _) = encoding.decode(&data).unwrap_err();
+            assert_eq!(code, game::Error::InvalidMessage, "{:?}", encoding);
+        }
+
+        let (code, _) = Encoding::Json.decode(b"{\"Move\":").unwrap_err();
+        assert_eq!(code, game::Error::InvalidJson);
+        // a map of one entry, cut off before it
+        for (encoding, marker) in [(Encoding::MessagePack, 0x81), (Encoding::Cbor, 0xa1)] {
+            let (code, _) = encoding.decode(&[marker]).unwrap_err();
+            assert_eq!(code, game::Error::InvalidEncoding, "{:?}", encoding);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Playing over server-sent events, for clients that can't open a WebSocket.
+// The game comes down an event stream, and the client's messages are POSTed
+// separately with its session.
+use crate::encoding::Encoding;
+use crate::game::{self, ToBrowser};
+use crate::server;
+use crate::socket::{SocketError, Transport};
+use axum::http::StatusCode;
+use axum::response::sse::Event;
+use futures_util::stream::{self, Stream};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Events waiting to be sent down a stream, and POSTed messages waiting to be
+/// handled. A client that falls further behind than this gets an error.
+const BUFFER: usize = 32;
+
+/// A new event stream, to be returned to the client as `Sse`, and the sender
+/// the game is sent down.
+pub fn channel() -> (
+    mpsc::Sender<Event>,
+    impl Stream<Item = Result<Event, Infallible>>,
+) {
+    let (tx, rx) = mpsc::channel(BUFFER);
+    let events = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok(event), rx))
+    });
+    (tx, events)
+}
+
+/// The server's end of a client's event stream, once it has joined a game.
+pub struct EventStream {
+    events: mpsc::Sender<Event>,
+    messages: mpsc::Receiver<String>,
+    state: Arc<server::State>,
+    session: String,
+}
+
+impl EventStream {
+    /// Start taking the messages POSTed with `session`. A stream already
+    /// opened with the same session stops receiving them, just as a player's
+    /// old WebSocket is replaced when they reconnect.
+    pub fn new(events: mpsc::Sender<Event>, state: Arc<server::State>, session: String) -> Self {
+        let (tx, messages) = mpsc::channel(BUFFER);
+        state
+            .event_streams
+            .lock()
+            .unwrap()
+            .insert(session.clone(), tx);
+        EventStream {
+            events,
+            messages,
+            state,
+            session,
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.messages.close();
+        let mut streams = self.state.event_streams.lock().unwrap();
+        // unless a newer stream has taken over the session
+        if streams.get(&self.session).is_some_and(|tx| tx.is_closed()) {
+            streams.remove(&self.session);
+        }
+    }
+}
+
+impl Transport for EventStream {
+    async fn send(&mut self, msg: &ToBrowser) -> Result<(), SocketError> {
+        send(&self.events, msg).await
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>, SocketError> {
+        tokio::select! {
+            text = self.messages.recv() => Ok(text.map(String::into_bytes)),
+            _ = self.events.closed() => Ok(None),
+        }
+    }
+
+    async fn keep_alive(&mut self) -> Result<(), SocketError> {
+        let ping = Event::default().comment("ping");
+        self.events
+            .send(ping)
+            .await
+            .map_err(|_| SocketError::Disconnected)
+    }
+
+    fn encoding(&self) -> Encoding {
+        Encoding::Json
+    }
+}
+
+async fn send(events: &mpsc::Sender<Event>, msg: &ToBrowser) -> Result<(), SocketError> {
+    let json = serde_json::to_string(msg).map_err(|e| SocketError::Encode(e.to_string()))?;
+    events
+        .send(Event::default().data(json))
+        .await
+        .map_err(|_| SocketError::Disconnected)
+}
+
+/// Finish with an event stream once its loop has ended, sending the client
+/// the error if it's one it can do something about. There's no close code,
+/// so clients should stop following the stream after an error like that, or
+/// the browser will reconnect to it.
+pub async fn close(events: mpsc::Sender<Event>, result: Result<(), SocketError>) {
+    let e = match result {
+        Ok(()) => {
+            debug!("Events: Client closed connection");
+            return;
+        }
+        Err(e) => e,
+    };
+    debug!("Events: Closing connection: {}", e);
+
+    let code = match e {
+        SocketError::Refused(code) => code,
+        SocketError::Encode(_) => game::Error::Internal,
+        _ => return,
+    };
+    let _ = send(&events, &code.into()).await;
+}
+
+/// Pass a message POSTed by a client on to its event stream.
+pub fn deliver(state: &server::State, session: &str, text: String) -> Result<(), StatusCode> {
+    let streams = state.event_streams.lock().unwrap();
+    let tx = streams.get(session).ok_or(StatusCode::NOT_FOUND)?;
+    tx.try_send(text).map_err(|e| match e {
+        mpsc::error::TrySendError::Full(_) => StatusCode::TOO_MANY_REQUESTS,
+        mpsc::error::TrySendError::Closed(_) => StatusCode::NOT_FOUND,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    fn server_state() -> Arc<server::State> {
+        let store = Arc::new(MemoryStore::new());
+        Arc::new(server::State::new(
+            "http://localhost/".to_string(),
+            store.clone(),
+            store,
+            Default::default(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn posted_messages_reach_the_sessions_stream() {
+        let state = server_state();
+        let (tx, _events) = channel();
+        let mut stream = EventStream::new(tx, state.clone(), "session".to_string());
+
+        deliver(&state, "session", "hello".to_string()).unwrap();
+        assert_eq!(stream.recv().await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(
+            deliver(&state, "someone else", "hello".to_string()),
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_that_fall_behind_are_told_to_slow_down() {
+        let state = server_state();
+        let (tx, _events) = channel();
+        let _stream = EventStream::new(tx, state.clone(), "session".to_string());
+        for _ in 0..BUFFER {
+            deliver(&state, "session", "{}".to_string()).unwrap();
+        }
+        assert_eq!(
+            deliver(&state, "session", "{}".to_string()),
+            Err(StatusCode::TOO_MANY_REQUESTS)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_newer_stream_takes_over_the_session() {
+        let state = server_state();
+        let (old_tx, _old_events) = channel();
+        let old = EventStream::new(old_tx, state.clone(), "session".to_string());
+        let (new_tx, _new_events) = channel();
+        let mut new = EventStream::new(new_tx, state.clone(), "session".to_string());
+
+        drop(old);
+        deliver(&state, "session", "still here".to_string()).unwrap();
+        assert_eq!(new.recv().await.unwrap(), Some(b"still here".to_vec()));
+
+        drop(new);
+        assert_eq!(
+            deliver(&state, "session", "gone".to_string()),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert!(state.event_streams.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_stream_ends_when_the_client_goes_away() {
+        let state = server_state();
+        let (tx, events) = channel();
+        let mut stream = EventStream::new(tx, state, "session".to_string());
+        drop(events);
+        assert_eq!(stream.recv().await.unwrap(), None);
+        assert!(matches!(
+            stream.send(&game::Error::GameOver.into()).await,
+            Err(SocketError::Disconnected)
+        ));
+    }
+}
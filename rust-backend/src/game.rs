@@ -1,7 +1,18 @@
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::bot;
+use crate::chat_filter::{self, Strictness, Verdict};
+use crate::metrics;
+use crate::patch;
+use crate::rating::{GameResult, Profile};
+use crate::tournament;
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use tokio::sync::watch;
+use tokio::sync::{mpsc, watch};
 use tracing::debug;
 
 #[derive(Debug)]
@@ -9,45 +20,626 @@ pub struct Game {
     pub id: String,
     pub state: State,
     pub state_changes: watch::Sender<State>,
+    /// Secret session tokens, which let a client take its place back after
+    /// its connection drops. Not part of `State`, so never broadcast.
+    sessions: HashMap<String, Session>,
+    last_connection_id: ConnectionID,
+    /// Where to send the results of rated games, see `report_results_to`.
+    results: Option<mpsc::UnboundedSender<GameResult>>,
+    /// Chat messages too old to be in `State`, oldest first, see `chat_page`.
+    chat_history: VecDeque<ChatMessage>,
+    /// Highest id given to a player or spectator so far, see `next_player_id`.
+    last_player_id: PlayerID,
+    /// People the host has banned from rejoining.
+    bans: Vec<Ban>,
+    /// Checks chat messages and names, see `filter_chat_with`.
+    chat_filter: Arc<chat_filter::Filter>,
+}
+
+/// Identifies one connection of a player, so that a stale connection closing
+/// doesn't disconnect the player from the connection that replaced it.
+pub type ConnectionID = u64;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Session {
+    player_id: PlayerID,
+    connection_id: ConnectionID,
+}
+
+/// Everything needed to bring a game back after a restart.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub state: State,
+    sessions: HashMap<String, Session>,
+    last_connection_id: ConnectionID,
+    #[serde(default)]
+    chat_history: VecDeque<ChatMessage>,
+    #[serde(default)]
+    last_player_id: PlayerID,
+    #[serde(default)]
+    bans: Vec<Ban>,
+}
+
+/// Someone the host banned. They can't rejoin with any of the sessions they
+/// had, or with their profile.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Ban {
+    player_id: PlayerID,
+    sessions: Vec<String>,
+    profile_id: Option<String>,
+}
+
+/// Player id and session of a seat reserved for a player who hasn't
+/// connected yet.
+pub type ReservedSeat = (PlayerID, String);
+
+/// Result of joining a game.
+#[derive(Debug, Clone)]
+pub struct Joined {
+    pub player_id: PlayerID,
+    pub role: Role,
+    pub session: String,
+    pub connection_id: ConnectionID,
+}
+
+/// Largest board a game may be created with.
+pub const MAX_BOARD_SIZE: usize = 19;
+
+/// Longest chat message, in bytes.
+pub const MAX_CHAT_LENGTH: usize = 500;
+
+/// Longest player name, in characters. Longer names are cut short.
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Latest chat messages kept in `State`. Older ones are moved to the game's
+/// history, and fetched with `FromBrowser::FetchChat`.
+pub const CHAT_IN_STATE: usize = 50;
+
+/// Chat messages kept in the history, beyond which the oldest are forgotten.
+pub const MAX_CHAT_HISTORY: usize = 500;
+
+/// Most chat messages sent in reply to one `FetchChat`.
+pub const CHAT_PAGE_SIZE: usize = 50;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Mode {
+    /// A single `board_size` square board, `win_length` in a row wins.
+    #[default]
+    Standard,
+    /// Nine 3x3 sub-boards arranged in a 3x3 meta-board. Where you play
+    /// within a sub-board picks the sub-board your opponent must play in.
+    Ultimate,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "standard" => Ok(Mode::Standard),
+            "ultimate" => Ok(Mode::Ultimate),
+            _ => Err(format!("Unknown game mode: {}", s)),
+        }
+    }
+}
+
+/// Per-game settings, chosen when the game is created.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Options {
+    pub mode: Mode,
+    /// Width and height of the (square) board. Always 9 in ultimate mode.
+    pub board_size: usize,
+    /// How many marks in a row are needed to win. Always 3 in ultimate mode.
+    pub win_length: usize,
+    /// Whether spectators may post in the chat.
+    pub spectator_chat: bool,
+    pub time_control: Option<TimeControl>,
+    /// Private games are left out of the lobby.
+    pub private: bool,
+    pub chat_filter: Strictness,
+}
+
+/// Longest time allowed in any time control, one hour.
+pub const MAX_CLOCK_MS: u64 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TimeControl {
+    /// Every move must be made within the time limit.
+    PerMove { move_ms: u64 },
+    /// Each player has a fixed amount of time for the whole game, plus an
+    /// increment added to their clock after each of their moves.
+    Game { initial_ms: u64, increment_ms: u64 },
+}
+
+impl TimeControl {
+    /// Time on a player's clock at the start of the game.
+    fn initial_ms(&self) -> u64 {
+        match *self {
+            TimeControl::PerMove { move_ms } => move_ms,
+            TimeControl::Game { initial_ms, .. } => initial_ms,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let (limit, increment) = match *self {
+            TimeControl::PerMove { move_ms } => (move_ms, 0),
+            TimeControl::Game {
+                initial_ms,
+                increment_ms,
+            } => (initial_ms, increment_ms),
+        };
+        if !(1000..=MAX_CLOCK_MS).contains(&limit) || increment > MAX_CLOCK_MS {
+            return Err("Time limits must be between 1 second and 1 hour".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            mode: Mode::Standard,
+            board_size: 3,
+            win_length: 3,
+            spectator_chat: true,
+            time_control: None,
+            private: false,
+            chat_filter: Strictness::default(),
+        }
+    }
+}
+
+impl Options {
+    pub fn ultimate() -> Self {
+        Options {
+            mode: Mode::Ultimate,
+            board_size: 9,
+            win_length: 3,
+            ..Options::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(time_control) = &self.time_control {
+            time_control.validate()?;
+        }
+        if self.mode == Mode::Ultimate {
+            if self.board_size != 9 || self.win_length != 3 {
+                return Err("Ultimate games are always played on a 9x9 board".to_string());
+            }
+            return Ok(());
+        }
+        if self.board_size < 3 || self.board_size > MAX_BOARD_SIZE {
+            return Err(format!(
+                "Board size must be between 3 and {}",
+                MAX_BOARD_SIZE
+            ));
+        }
+        if self.win_length < 3 || self.win_length > self.board_size {
+            return Err("Win length must be between 3 and the board size".to_string());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct State {
+    /// Goes up by one every time the state is broadcast, see `patch`.
+    #[serde(default)]
+    pub version: u64,
+    pub mode: Mode,
     pub turn: char,
     pub winner: Option<EndState>,
+    /// How the game ended, set along with `winner`.
+    #[serde(default)]
+    pub end_reason: Option<EndReason>,
     pub players: Vec<Player>,
+    /// Connections beyond the two seated players, who can only watch.
+    pub spectators: Vec<Spectator>,
+    /// Players and spectators the host has muted in chat.
+    #[serde(default)]
+    pub muted: Vec<PlayerID>,
+    pub spectator_chat: bool,
+    #[serde(default)]
+    pub chat_filter: Strictness,
+    /// In standard mode, row-major, `board_size * board_size` cells.
+    /// In ultimate mode, the nine sub-boards one after another, each
+    /// row-major, so space `s` is cell `s % 9` of sub-board `s / 9`.
     pub board: Vec<char>,
+    pub board_size: usize,
+    pub win_length: usize,
+    /// Only present in ultimate mode.
+    pub ultimate: Option<UltimateState>,
+    /// Every move of the current game, in order.
+    #[serde(default)]
+    pub moves: Vec<MoveRecord>,
+    /// Player waiting for their opponent to agree to a takeback.
+    pub takeback_request: Option<PlayerID>,
+    /// Player waiting for their opponent to agree to a draw. Their opponent
+    /// moving instead declines it.
+    #[serde(default)]
+    pub draw_offer: Option<PlayerID>,
+    /// Rematch waiting for the opponent to accept.
+    #[serde(default)]
+    pub rematch_offer: Option<RematchOffer>,
+    pub time_control: Option<TimeControl>,
+    /// When the clock of the side to move started running, in milliseconds
+    /// since the Unix epoch. None while the clocks are stopped. The time
+    /// remaining is the player's `clock_ms` minus the time since then.
+    pub turn_started_at: Option<u64>,
+    /// Milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub created_at: u64,
+    /// Hidden from the lobby, only joinable by token.
+    #[serde(default)]
+    pub private: bool,
+    /// Id of the tournament this game is a match of. Its seats are reserved
+    /// for the entrants, and it can't be rematched or taken back.
+    pub tournament: Option<String>,
+    /// The latest `CHAT_IN_STATE` messages, oldest first.
     pub chat: Vec<ChatMessage>,
 }
 
+/// A game with a free seat, as listed in the lobby.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LobbyGame {
+    pub token: String,
+    /// Name of the player waiting for an opponent.
+    pub host: String,
+    pub mode: Mode,
+    pub board_size: usize,
+    pub win_length: usize,
+    pub time_control: Option<TimeControl>,
+    pub spectators: usize,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MoveRecord {
+    pub player_id: PlayerID,
+    pub team: char,
+    pub space: usize,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RematchOffer {
+    pub player_id: PlayerID,
+    /// When the offer lapses, in milliseconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// How long the opponent has to accept a rematch.
+pub const REMATCH_OFFER_MS: u64 = 30 * 1000;
+
+/// Meta-board of an ultimate game.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UltimateState {
+    /// Result of each sub-board, row-major; None while still being played.
+    pub sub_boards: Vec<Option<EndState>>,
+    /// The sub-board the next move must be made in, or None when the player
+    /// may pick any undecided sub-board.
+    pub next_sub_board: Option<usize>,
+}
+
+impl UltimateState {
+    fn new() -> UltimateState {
+        UltimateState {
+            sub_boards: vec![None; 9],
+            next_sub_board: None,
+        }
+    }
+}
+
 impl State {
-    pub fn new() -> State {
+    pub fn new(options: Options) -> State {
         State {
+            version: 0,
+            mode: options.mode,
             turn: 'X',
             winner: None,
+            end_reason: None,
             players: Vec::new(),
-            board: vec![' ', ' ', ' ', ' ', ' ', ' ', ' ', ' ', ' '],
+            spectators: Vec::new(),
+            muted: Vec::new(),
+            spectator_chat: options.spectator_chat,
+            chat_filter: options.chat_filter,
+            board: vec![' '; options.board_size * options.board_size],
+            board_size: options.board_size,
+            win_length: options.win_length,
+            ultimate: match options.mode {
+                Mode::Standard => None,
+                Mode::Ultimate => Some(UltimateState::new()),
+            },
+            moves: Vec::new(),
+            takeback_request: None,
+            draw_offer: None,
+            rematch_offer: None,
+            time_control: options.time_control,
+            turn_started_at: None,
+            created_at: unix_millis(),
+            private: options.private,
+            tournament: None,
             chat: Vec::new(),
         }
     }
+
+    /// Profile ids of the X and O players, if the game counts towards their
+    /// ratings: both players are different people who joined with profiles.
+    pub fn rated_profiles(&self) -> Option<(String, String)> {
+        let profile = |team| {
+            self.players
+                .iter()
+                .find(|p| p.team == team && p.bot.is_none())
+                .and_then(|p| p.profile_id.clone())
+        };
+        match (profile('X'), profile('O')) {
+            (Some(x), Some(o)) if x != o => Some((x, o)),
+            _ => None,
+        }
+    }
+
+    /// The longest-seated human player, who may change the game's settings.
+    pub fn host(&self) -> Option<&Player> {
+        self.players.iter().find(|p| p.bot.is_none())
+    }
+
+    /// Whether a player or spectator with this id is in the game.
+    pub fn is_present(&self, id: PlayerID) -> bool {
+        self.players.iter().any(|p| p.id == id) || self.spectators.iter().any(|s| s.id == id)
+    }
+
+    /// How the game appears in the lobby, or None if it shouldn't be listed:
+    /// it is private, or doesn't have exactly one connected player waiting.
+    pub fn lobby_game(&self, token: &str) -> Option<LobbyGame> {
+        if self.private || self.players.len() != 1 {
+            return None;
+        }
+        let host = self.host().filter(|p| p.connected)?;
+        Some(LobbyGame {
+            token: token.to_string(),
+            host: host.name.clone(),
+            mode: self.mode,
+            board_size: self.board_size,
+            win_length: self.win_length,
+            time_control: self.time_control,
+            spectators: self.spectators.len(),
+            created_at: self.created_at,
+        })
+    }
+
+    /// Empty the board for a new game, keeping the players and chat.
+    pub fn clear_board(&mut self) {
+        self.board.iter_mut().for_each(|c| *c = ' ');
+        self.turn = 'X';
+        self.winner = None;
+        self.end_reason = None;
+        if let Some(ultimate) = &mut self.ultimate {
+            *ultimate = UltimateState::new();
+        }
+        self.moves.clear();
+        self.takeback_request = None;
+        self.draw_offer = None;
+    }
+
+    /// Spaces the side to move may play, in board order.
+    pub fn legal_moves(&self) -> Vec<usize> {
+        if self.winner.is_some() {
+            return Vec::new();
+        }
+        (0..self.board.len())
+            .filter(|&space| self.is_legal(space))
+            .collect()
+    }
+
+    fn is_legal(&self, space: usize) -> bool {
+        self.check_move(space).is_ok()
+    }
+
+    fn check_move(&self, space: usize) -> Result<(), Error> {
+        match self.board.get(space) {
+            None => return Err(Error::SpaceOutOfRange),
+            Some(' ') => {}
+            Some(_) => return Err(Error::SpaceOccupied),
+        }
+        match &self.ultimate {
+            None => Ok(()),
+            Some(ultimate) => {
+                let sub_board = space / 9;
+                if ultimate.sub_boards[sub_board].is_some() {
+                    Err(Error::SubBoardFinished)
+                } else if ultimate.next_sub_board.unwrap_or(sub_board) != sub_board {
+                    Err(Error::WrongSubBoard)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Place the mark of the side to move, pass the turn, and record the
+    /// winner if the move ended the game. Knows nothing about players, so it
+    /// is also used to explore positions without touching a live game.
+    pub fn play(&mut self, space: usize) -> Result<(), Error> {
+        if self.winner.is_some() {
+            return Err(Error::GameOver);
+        }
+        self.check_move(space)?;
+
+        let team = self.turn;
+        self.board[space] = team;
+        self.turn = if self.turn == 'X' { 'O' } else { 'X' };
+
+        match self.ultimate.as_mut() {
+            None => {
+                if completes_line(&self.board, self.board_size, self.win_length, space) {
+                    self.winner = Some(EndState::Win(team));
+                } else if self.board.iter().all(|&c| c != ' ') {
+                    self.winner = Some(EndState::Draw);
+                }
+            }
+            Some(ultimate) => {
+                let sub_board = space / 9;
+                let cells = &self.board[sub_board * 9..sub_board * 9 + 9];
+                if completes_line(cells, 3, 3, space % 9) {
+                    ultimate.sub_boards[sub_board] = Some(EndState::Win(team));
+                } else if cells.iter().all(|&c| c != ' ') {
+                    ultimate.sub_boards[sub_board] = Some(EndState::Draw);
+                }
+
+                // drawn sub-boards count for nobody on the meta-board
+                let meta: Vec<char> = ultimate
+                    .sub_boards
+                    .iter()
+                    .map(|result| match result {
+                        Some(EndState::Win(t)) => *t,
+                        _ => ' ',
+                    })
+                    .collect();
+                if completes_line(&meta, 3, 3, sub_board) {
+                    self.winner = Some(EndState::Win(team));
+                } else if ultimate.sub_boards.iter().all(|result| result.is_some()) {
+                    self.winner = Some(EndState::Draw);
+                }
+
+                let target = space % 9;
+                ultimate.next_sub_board = if ultimate.sub_boards[target].is_none() {
+                    Some(target)
+                } else {
+                    None
+                };
+            }
+        }
+        if self.winner.is_some() {
+            self.end_reason = Some(EndReason::Line);
+        }
+
+        Ok(())
+    }
+
+    /// When the clock of the side to move will run out, in milliseconds since
+    /// the Unix epoch, if it is running.
+    pub fn clock_deadline(&self) -> Option<u64> {
+        let started_at = self.turn_started_at?;
+        let player = self.players.iter().find(|p| p.team == self.turn)?;
+        Some(started_at + player.clock_ms?)
+    }
+
+    /// The next time the server has to check on the game, when a clock runs
+    /// out or a rematch offer lapses.
+    pub fn next_deadline(&self) -> Option<u64> {
+        let offer_expires_at = self.rematch_offer.as_ref().map(|o| o.expires_at);
+        self.clock_deadline()
+            .into_iter()
+            .chain(offer_expires_at)
+            .min()
+    }
+
+    /// 1-based (column, row) of a space, as seen on screen.
+    pub fn coordinates(&self, space: usize) -> (usize, usize) {
+        match self.mode {
+            Mode::Standard => (space % self.board_size + 1, space / self.board_size + 1),
+            Mode::Ultimate => {
+                let (sub_board, cell) = (space / 9, space % 9);
+                (
+                    (sub_board % 3) * 3 + cell % 3 + 1,
+                    (sub_board / 3) * 3 + cell / 3 + 1,
+                )
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Whether the mark at `space` is part of a line of at least `win_length` on a
+/// row-major `size` square board. Only lines passing through the most recent
+/// move can have been completed by it, so scan outwards from that space in
+/// each of the four directions instead of checking every possible line.
+fn completes_line(board: &[char], size: usize, win_length: usize, space: usize) -> bool {
+    let size = size as isize;
+    let team = board[space];
+    if team == ' ' {
+        return false;
+    }
+
+    let row = space as isize / size;
+    let col = space as isize % size;
+    let count_direction = |dr: isize, dc: isize| {
+        let mut count = 0;
+        let (mut r, mut c) = (row + dr, col + dc);
+        while r >= 0 && r < size && c >= 0 && c < size && board[(r * size + c) as usize] == team {
+            count += 1;
+            r += dr;
+            c += dc;
+        }
+        count
+    };
+
+    [(0, 1), (1, 0), (1, 1), (1, -1)]
+        .iter()
+        .any(|&(dr, dc)| 1 + count_direction(dr, dc) + count_direction(-dr, -dc) >= win_length)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum EndState {
     Win(char),
     Draw,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum EndReason {
+    /// A line was completed, or the board filled up without one.
+    Line,
+    /// The side to move ran out of time.
+    Timeout,
+    Resignation,
+    /// The players agreed to a draw.
+    Agreement,
+    /// A player didn't show up for a tournament match.
+    Forfeit,
+    /// A player left partway through and didn't come back in time.
+    Abandonment,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Player {
     pub id: PlayerID,
     pub team: char,
     pub name: String,
     pub wins: i32,
+    /// Set for server-side computer players.
+    #[serde(default)]
+    pub bot: Option<bot::Strategy>,
+    /// False while the seat is being held for the player to reconnect.
+    pub connected: bool,
+    /// Time left on this player's clock when their turn started, in
+    /// milliseconds. None in games without a time control.
+    pub clock_ms: Option<u64>,
+    /// Public id of the player's profile, if they joined with a device key.
+    #[serde(default)]
+    pub profile_id: Option<String>,
+    #[serde(default)]
+    pub rating: Option<i32>,
+}
+
+/// Spectators share the `PlayerID` space with players.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Spectator {
+    pub id: PlayerID,
+    pub name: String,
 }
 
 pub type PlayerID = i32;
 
+/// Whether a connection holds a seat or is just watching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Role {
+    Player,
+    Spectator,
+}
+
 impl Display for Player {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{} ({})", self.name, self.team)
@@ -64,251 +656,2053 @@ pub struct ChatMessage {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ChatMessageSource {
     Player(PlayerID),
+    Spectator(PlayerID),
     System,
 }
 
 impl Game {
-    pub fn new(id: String) -> (Game, watch::Receiver<State>) {
-        let state = State::new();
+    pub fn new(id: String, options: Options) -> (Game, watch::Receiver<State>) {
+        let state = State::new(options);
         let (tx, rx) = watch::channel(state.clone());
 
         let game = Game {
-            id: id,
-            state: state,
+            id,
+            state,
             state_changes: tx,
+            sessions: HashMap::new(),
+            last_connection_id: 0,
+            results: None,
+            chat_history: VecDeque::new(),
+            last_player_id: 0,
+            bans: Vec::new(),
+            chat_filter: Arc::default(),
         };
 
-        return (game, rx);
+        (game, rx)
     }
 
-    pub fn add_player(&mut self, name: String) -> Result<Player, String> {
-        if self.state.players.len() >= 2 {
-            return Err("Game is full".to_string());
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            id: self.id.clone(),
+            state: self.state.clone(),
+            sessions: self.sessions.clone(),
+            last_connection_id: self.last_connection_id,
+            chat_history: self.chat_history.clone(),
+            last_player_id: self.last_player_id,
+            bans: self.bans.clone(),
         }
+    }
 
-        let last_player = self.state.players.last();
+    /// Recreate a saved game. Nobody is connected to it any more, so
+    /// spectators are dropped and every human player's seat is held for them
+    /// as if their connection had just dropped, see `held_seats`.
+    pub fn from_snapshot(snapshot: Snapshot) -> (Game, watch::Receiver<State>) {
+        let mut state = snapshot.state;
+        // don't charge anyone for the time the server was down
+        if state.turn_started_at.is_some() {
+            state.turn_started_at = Some(unix_millis());
+        }
+        let spectators: Vec<PlayerID> = state.spectators.drain(..).map(|s| s.id).collect();
+        state
+            .players
+            .iter_mut()
+            .filter(|p| p.bot.is_none())
+            .for_each(|p| p.connected = false);
 
-        let id = last_player.map(|p| p.id + 1).unwrap_or(1);
-        let team = match last_player.map(|p| p.team) {
-            Some('X') => 'O',
-            _ => 'X',
-        };
+        let mut sessions = snapshot.sessions;
+        sessions.retain(|_, s| !spectators.contains(&s.player_id));
 
-        let player = Player {
-            id: id,
-            team: team,
-            name: name,
-            wins: 0,
+        let (tx, rx) = watch::channel(state.clone());
+        let mut game = Game {
+            id: snapshot.id,
+            state,
+            state_changes: tx,
+            sessions,
+            last_connection_id: snapshot.last_connection_id,
+            results: None,
+            chat_history: snapshot.chat_history,
+            last_player_id: snapshot.last_player_id,
+            bans: snapshot.bans,
+            chat_filter: Arc::default(),
         };
-        self.state.players.push(player.clone());
-        self.add_chat_message(
-            ChatMessageSource::System,
-            format!("{} ({}) has joined the game", player.name, player.team),
-        );
-        Ok(player)
+        // saved before the chat was capped
+        game.trim_chat();
+        (game, rx)
     }
 
-    /// internal trusted function that always succeeds unless the id is bad
-    fn update_player_name(&mut self, id: PlayerID, name: String) -> Result<(), String> {
-        let player = self.get_player_mut(id).ok_or("Invalid player ID")?;
-        player.name = name;
-        Ok(())
+    /// Send the result of every rated game played from now on to `tx`.
+    pub fn report_results_to(&mut self, tx: mpsc::UnboundedSender<GameResult>) {
+        self.results = Some(tx);
     }
 
-    /// Internal trusted version
-    fn add_chat_message(&mut self, source: ChatMessageSource, text: String) {
-        let id = self.state.chat.len();
-        self.state.chat.push(ChatMessage {
-            id: id,
-            source: source,
-            text: text,
-        });
+    /// Check chat messages and names with `filter`, at the strictness the
+    /// game was created with.
+    pub fn filter_chat_with(&mut self, filter: Arc<chat_filter::Filter>) {
+        self.chat_filter = filter;
     }
 
-    pub fn get_player_index(&self, id: PlayerID) -> Option<usize> {
-        self.state.players.iter().position(|p| p.id == id)
+    /// Show players' new ratings once a result has been recorded.
+    pub fn update_ratings(&mut self, profiles: &[Profile]) {
+        let mut changes = Vec::new();
+        for player in self.state.players.iter_mut() {
+            let profile = match profiles
+                .iter()
+                .find(|p| player.profile_id.as_ref() == Some(&p.id))
+            {
+                Some(profile) => profile,
+                None => continue,
+            };
+            let old = player.rating.unwrap_or(profile.rating);
+            player.rating = Some(profile.rating);
+            changes.push(format!(
+                "{} {} ({:+})",
+                player.name,
+                profile.rating,
+                profile.rating - old
+            ));
+        }
+        if !changes.is_empty() {
+            self.add_chat_message(
+                ChatMessageSource::System,
+                format!("New ratings: {}", changes.join(", ")),
+            );
+        }
     }
 
-    pub fn get_player_index_by_team(&self, team: char) -> Option<usize> {
-        self.state.players.iter().position(|p| p.team == team)
+    /// Players waiting to reconnect, with the connection they were last on.
+    pub fn held_seats(&self) -> Vec<(PlayerID, ConnectionID)> {
+        self.state
+            .players
+            .iter()
+            .filter(|p| !p.connected)
+            .filter_map(|p| {
+                self.sessions
+                    .values()
+                    .find(|s| s.player_id == p.id)
+                    .map(|s| (p.id, s.connection_id))
+            })
+            .collect()
     }
 
-    pub fn get_player_mut(&mut self, id: PlayerID) -> Option<&mut Player> {
-        self.state.players.iter_mut().find(|p| p.id == id)
+    pub fn add_player(&mut self, name: String) -> Result<Player, Error> {
+        self.seat_player(name, None)
     }
 
-    pub fn remove_player(&mut self, id: PlayerID) {
-        let player = match self.state.players.iter().find(|p| p.id == id) {
-            Some(p) => p,
-            None => return,
+    fn add_player_with_profile(
+        &mut self,
+        name: String,
+        profile: Option<&Profile>,
+    ) -> Result<Player, Error> {
+        let id = self.add_player(name)?.id;
+        let player = self.get_player_mut(id).ok_or(Error::InvalidPlayer)?;
+        if let Some(profile) = profile {
+            player.profile_id = Some(profile.id.clone());
+            player.rating = Some(profile.rating);
+        }
+        Ok(player.clone())
+    }
+
+    /// Seat a player who hasn't connected yet, returning the session they
+    /// can join with. The seat is held until they do, see `has_connected`.
+    pub fn reserve_seat(&mut self, name: String) -> Result<ReservedSeat, Error> {
+        let player = self.add_player(name)?;
+        if let Some(seated) = self.get_player_mut(player.id) {
+            seated.connected = false;
+        }
+
+        let session = random_token(32);
+        self.sessions.insert(
+            session.clone(),
+            Session {
+                player_id: player.id,
+                // real connections start from 1
+                connection_id: 0,
+            },
+        );
+        Ok((player.id, session))
+    }
+
+    /// Whether a player has connected since their seat was reserved.
+    pub fn has_connected(&self, player_id: PlayerID) -> bool {
+        self.sessions
+            .values()
+            .any(|s| s.player_id == player_id && s.connection_id != 0)
+    }
+
+    /// Whether a player's seat is reserved and they haven't arrived yet.
+    fn awaiting_arrival(&self, player_id: PlayerID) -> bool {
+        self.sessions
+            .values()
+            .any(|s| s.player_id == player_id && s.connection_id == 0)
+    }
+
+    /// Give up the seats of everyone not connected, once a tournament match
+    /// is over, so that the game can be cleaned up when the rest leave.
+    pub fn release_absent_players(&mut self) {
+        let absent: Vec<PlayerID> = self
+            .state
+            .players
+            .iter()
+            .filter(|p| !p.connected)
+            .map(|p| p.id)
+            .collect();
+        for player_id in absent {
+            self.remove_player(player_id);
+        }
+    }
+
+    pub fn add_spectator(&mut self, name: String) -> Spectator {
+        let spectator = Spectator {
+            id: self.next_player_id(),
+            name,
         };
+        self.state.spectators.push(spectator.clone());
         self.add_chat_message(
             ChatMessageSource::System,
-            format!("{} has left the game", player.name),
+            format!("{} is now watching", spectator.name),
         );
-        self.state.players.retain(|p| p.id != id);
+        spectator
     }
 
-    pub fn take_turn(&mut self, player_id: PlayerID, space: usize) -> Result<(), String> {
-        if self.state.players.len() < 2 {
-            return Err("Not enough players".to_string());
+    /// Take back the place belonging to `session` if it is still held,
+    /// otherwise take a seat if there is one free, otherwise watch. A seated
+    /// player's games are rated if they and their opponent have profiles.
+    pub fn join(
+        &mut self,
+        name: String,
+        session: Option<&str>,
+        profile: Option<&Profile>,
+    ) -> Result<Joined, Error> {
+        if self.is_banned(session, profile) {
+            return Err(Error::Banned);
         }
-
-        if self.state.winner.is_some() {
-            return Err("Game is over".to_string());
+        if let Some(joined) = session.and_then(|token| self.resume(token)) {
+            return Ok(joined);
         }
+        let name = match self.filter_text(clip_name(&name)) {
+            Ok(Some(name)) => name,
+            _ => "Unnamed Player".to_string(),
+        };
 
-        let player_idx = match self.get_player_index(player_id) {
-            Some(idx) => idx,
-            None => return Err("Invalid player ID".to_string()),
+        let seated = if self.state.players.len() < 2 && self.state.tournament.is_none() {
+            self.add_player_with_profile(name.clone(), profile).ok()
+        } else {
+            None
+        };
+        let (player_id, role) = match seated {
+            Some(player) => (player.id, Role::Player),
+            None => (self.add_spectator(name).id, Role::Spectator),
         };
-        let team = self.state.players[player_idx].team;
 
-        if self.state.turn != self.state.players[player_idx].team {
-            return Err("Not your turn".to_string());
-        }
+        let session = random_token(32);
+        let connection_id = self.next_connection_id();
+        self.sessions.insert(
+            session.clone(),
+            Session {
+                player_id,
+                connection_id,
+            },
+        );
 
-        if self.state.board[space] != ' ' {
-            return Err("Invalid move".to_string());
-        }
+        Ok(Joined {
+            player_id,
+            role,
+            session,
+            connection_id,
+        })
+    }
 
-        self.state.board[space] = team;
-        self.state.turn = if self.state.turn == 'X' { 'O' } else { 'X' };
+    fn resume(&mut self, token: &str) -> Option<Joined> {
+        let player_id = self.sessions.get(token)?.player_id;
+        let role = if self.is_spectator(player_id) {
+            Role::Spectator
+        } else {
+            Role::Player
+        };
 
-        self.add_chat_message(
-            ChatMessageSource::Player(player_id),
-            format!("Played {} at ({}, {}).", team, space % 3 + 1, space / 3 + 1),
-        );
+        let connection_id = self.next_connection_id();
+        let first_connection = self.sessions.get(token)?.connection_id == 0;
+        self.sessions.get_mut(token)?.connection_id = connection_id;
 
-        if let Some(winning_team) = self.check_for_win() {
-            self.state.winner = Some(EndState::Win(winning_team));
-            let winner_idx = self.get_player_index_by_team(winning_team).unwrap();
-            self.state.players[winner_idx].wins += 1;
-            self.add_chat_message(
-                ChatMessageSource::System,
-                format!("{} wins!", self.state.players[winner_idx]),
-            );
-        } else if self.check_for_draw() {
-            self.state.winner = Some(EndState::Draw);
-            self.add_chat_message(ChatMessageSource::System, "It's a draw!".to_string());
+        if let Some(player) = self.get_player_mut(player_id) {
+            player.connected = true;
+            let text = if first_connection {
+                format!("{} has arrived", player)
+            } else {
+                format!("{} has reconnected", player)
+            };
+            self.add_chat_message(ChatMessageSource::System, text);
+            if first_connection {
+                self.restart_clock();
+            }
         }
 
-        Ok(())
+        Some(Joined {
+            player_id,
+            role,
+            session: token.to_string(),
+            connection_id,
+        })
     }
 
-    pub fn broadcast_state(&self) {
-        self.state_changes.send_replace(self.state.clone());
+    fn next_connection_id(&mut self) -> ConnectionID {
+        self.last_connection_id += 1;
+        self.last_connection_id
     }
 
-    fn check_for_win(&self) -> Option<char> {
-        let winning_combos = [
-            [0, 1, 2],
-            [3, 4, 5],
-            [6, 7, 8],
-            [0, 3, 6],
-            [1, 4, 7],
-            [2, 5, 8],
-            [0, 4, 8],
-            [2, 4, 6],
-        ];
+    fn is_current_connection(&self, player_id: PlayerID, connection_id: ConnectionID) -> bool {
+        self.sessions
+            .values()
+            .any(|s| s.player_id == player_id && s.connection_id == connection_id)
+    }
 
-        for combo in winning_combos.iter() {
-            let mut winner = self.state.board[combo[0]];
-            if winner == ' ' {
-                continue;
-            }
+    /// A connection closed. Spectators leave straight away, but a player's
+    /// seat is held for them, and true is returned if the caller should
+    /// call `expire_seat` once the grace period is over.
+    pub fn disconnect(&mut self, player_id: PlayerID, connection_id: ConnectionID) -> bool {
+        if !self.is_current_connection(player_id, connection_id) {
+            // the player has already reconnected elsewhere
+            return false;
+        }
 
-            for i in 1..3 {
-                if self.state.board[combo[i]] != winner {
-                    winner = ' ';
-                    break;
-                }
-            }
+        if self.is_spectator(player_id) {
+            self.remove_player(player_id);
+            return false;
+        }
 
-            if winner != ' ' {
-                return Some(winner);
-            }
+        let player = match self.get_player_mut(player_id) {
+            Some(p) => p,
+            None => return false,
+        };
+        player.connected = false;
+        let text = format!("{} lost connection", player);
+        self.add_chat_message(ChatMessageSource::System, text);
+        true
+    }
+
+    /// Give up a held seat if its player never came back. Seats in tournament
+    /// games are kept until the match is over, see `release_absent_players`.
+    pub fn expire_seat(&mut self, player_id: PlayerID, connection_id: ConnectionID) {
+        if self.state.tournament.is_some() && self.state.winner.is_none() {
+            return;
+        }
+        let still_gone = self
+            .state
+            .players
+            .iter()
+            .any(|p| p.id == player_id && !p.connected);
+        if still_gone && self.is_current_connection(player_id, connection_id) {
+            self.forfeit_abandoned(player_id);
+            self.remove_player(player_id);
         }
+    }
 
-        None
+    /// Award a game in progress to the opponent of a player who left it, if
+    /// they're still here. The board stays as it was until someone takes the
+    /// empty seat, see `seat_player`.
+    fn forfeit_abandoned(&mut self, player_id: PlayerID) {
+        if self.state.winner.is_some() || self.state.moves.is_empty() {
+            return;
+        }
+        let opponent = self
+            .state
+            .players
+            .iter()
+            .find(|p| p.id != player_id && p.connected)
+            .map(|p| p.team);
+        if let Some(team) = opponent {
+            let idx = self.get_player_index(player_id).unwrap();
+            let text = format!("{} abandoned the game.", self.state.players[idx]);
+            self.add_chat_message(ChatMessageSource::System, text);
+            self.end_game(EndState::Win(team), EndReason::Abandonment);
+        }
     }
 
-    fn check_for_draw(&self) -> bool {
-        self.state.board.iter().all(|&c| c != ' ')
+    /// Ids are never reused, so that the connection of someone who was
+    /// removed can't act as whoever joins next.
+    fn next_player_id(&mut self) -> PlayerID {
+        let highest = self
+            .state
+            .players
+            .iter()
+            .map(|p| p.id)
+            .chain(self.state.spectators.iter().map(|s| s.id))
+            .max()
+            .unwrap_or(0);
+        self.last_player_id = self.last_player_id.max(highest) + 1;
+        self.last_player_id
     }
 
-    fn reset(&mut self) {
-        self.state.board.iter_mut().for_each(|c| *c = ' ');
-        self.state.turn = 'X';
-        self.state.winner = None;
+    pub fn is_spectator(&self, id: PlayerID) -> bool {
+        self.state.spectators.iter().any(|s| s.id == id)
     }
-    fn swap_teams(&mut self) {
-        self.state.players.iter_mut().for_each(|p| {
-            if p.team == 'X' {
-                p.team = 'O';
-            } else {
-                p.team = 'X';
-            }
-        });
+
+    /// Seat a computer player. Something else has to actually make its moves,
+    /// see `server::add_bot`.
+    pub fn add_bot(&mut self, strategy: bot::Strategy) -> Result<Player, Error> {
+        self.seat_player(format!("Computer ({})", strategy), Some(strategy))
     }
 
-    pub fn handle_msg(&mut self, player_id: PlayerID, msg: FromBrowser) -> Result<bool, String> {
-        debug!("Game: Handle Msg: {:?}", msg);
-        match msg {
-            FromBrowser::ChatMsg { text } => {
-                let trimmed = text.trim();
-                if trimmed.len() == 0 {
-                    return Err("Empty message".to_string());
-                }
-                if trimmed.len() > 500 {
-                    return Err("Message too long".to_string());
-                }
-                self.add_chat_message(ChatMessageSource::Player(player_id), trimmed.to_string());
-            }
-            FromBrowser::ChangeName { new_name } => {
-                let mut trimmed = new_name.trim();
-                if trimmed.len() == 0 {
-                    trimmed = "Unnamed Player";
-                } else if trimmed.len() > 32 {
-                    trimmed = &trimmed[..32];
-                }
-                self.update_player_name(player_id, trimmed.to_string())
-                    .unwrap();
-                self.add_chat_message(
-                    ChatMessageSource::Player(player_id),
-                    format!("Now my name is \"{}\"!", new_name),
-                );
-            }
-            FromBrowser::Move { space } => self.take_turn(player_id, space)?,
-            FromBrowser::Rematch => {
-                self.add_chat_message(ChatMessageSource::Player(player_id), "Rematch!".to_string());
+    fn seat_player(&mut self, name: String, bot: Option<bot::Strategy>) -> Result<Player, Error> {
+        if self.state.players.len() >= 2 {
+            return Err(Error::GameFull);
+        }
+
+        let id = self.next_player_id();
+        let team = match self.state.players.last().map(|p| p.team) {
+            Some('X') => 'O',
+            _ => 'X',
+        };
+
+        // the last opponent walked out, so start again with the new one
+        let fresh_game = self.state.end_reason == Some(EndReason::Abandonment);
+        if fresh_game {
+            self.state.clear_board();
+        }
+
+        let player = Player {
+            id,
+            team,
+            name,
+            wins: 0,
+            bot,
+            connected: true,
+            clock_ms: self.state.time_control.map(|tc| tc.initial_ms()),
+            profile_id: None,
+            rating: None,
+        };
+        self.state.players.push(player.clone());
+        self.add_chat_message(
+            ChatMessageSource::System,
+            format!("{} ({}) has joined the game", player.name, player.team),
+        );
+        if fresh_game {
+            self.reset_clocks();
+        } else {
+            self.restart_clock();
+        }
+        Ok(player)
+    }
+
+    /// internal trusted function that always succeeds unless the id is bad
+    fn update_player_name(&mut self, id: PlayerID, name: String) -> Result<(), Error> {
+        if let Some(spectator) = self.state.spectators.iter_mut().find(|s| s.id == id) {
+            spectator.name = name;
+            return Ok(());
+        }
+        let player = self.get_player_mut(id).ok_or(Error::InvalidPlayer)?;
+        player.name = name;
+        Ok(())
+    }
+
+    /// Run a chat message or name through the filter, returning None if it
+    /// should be quietly dropped.
+    fn filter_text(&self, text: &str) -> Result<Option<String>, Error> {
+        match self.chat_filter.check(text, self.state.chat_filter) {
+            Verdict::Allow(text) => Ok(Some(text)),
+            Verdict::Reject(e) => Err(e),
+            Verdict::Drop => Ok(None),
+        }
+    }
+
+    /// Who a chat message from this id should be attributed to.
+    fn chat_source(&self, id: PlayerID) -> ChatMessageSource {
+        if self.is_spectator(id) {
+            ChatMessageSource::Spectator(id)
+        } else {
+            ChatMessageSource::Player(id)
+        }
+    }
+
+    /// Internal trusted version
+    fn add_chat_message(&mut self, source: ChatMessageSource, text: String) {
+        let id = self
+            .state
+            .chat
+            .last()
+            .or(self.chat_history.back())
+            .map_or(0, |m| m.id + 1);
+        self.state.chat.push(ChatMessage { id, source, text });
+        self.trim_chat();
+    }
+
+    /// Move all but the latest `CHAT_IN_STATE` messages out of the state and
+    /// into the history, forgetting the oldest beyond `MAX_CHAT_HISTORY`.
+    fn trim_chat(&mut self) {
+        let moved = self.state.chat.len().saturating_sub(CHAT_IN_STATE);
+        self.chat_history.extend(self.state.chat.drain(..moved));
+        let forgotten = self.chat_history.len().saturating_sub(MAX_CHAT_HISTORY);
+        self.chat_history.drain(..forgotten);
+    }
+
+    /// Up to `CHAT_PAGE_SIZE` of the latest chat messages with ids below
+    /// `before`, oldest first, and whether there are older ones still.
+    pub fn chat_page(&self, before: usize) -> (Vec<ChatMessage>, bool) {
+        let older: Vec<&ChatMessage> = self
+            .chat_history
+            .iter()
+            .chain(&self.state.chat)
+            .take_while(|m| m.id < before)
+            .collect();
+        let start = older.len().saturating_sub(CHAT_PAGE_SIZE);
+        let page = older[start..].iter().map(|&m| m.clone()).collect();
+        (page, start > 0)
+    }
+
+    pub fn get_player_index(&self, id: PlayerID) -> Option<usize> {
+        self.state.players.iter().position(|p| p.id == id)
+    }
+
+    pub fn get_player_index_by_team(&self, team: char) -> Option<usize> {
+        self.state.players.iter().position(|p| p.team == team)
+    }
+
+    pub fn get_player_mut(&mut self, id: PlayerID) -> Option<&mut Player> {
+        self.state.players.iter_mut().find(|p| p.id == id)
+    }
+
+    pub fn remove_player(&mut self, id: PlayerID) {
+        let text = if let Some(spectator) = self.state.spectators.iter().find(|s| s.id == id) {
+            format!("{} stopped watching", spectator.name)
+        } else if let Some(player) = self.state.players.iter().find(|p| p.id == id) {
+            format!("{} has left the game", player.name)
+        } else {
+            return;
+        };
+        self.add_chat_message(ChatMessageSource::System, text);
+        self.unseat(id);
+    }
+
+    /// Take a player or spectator out of the game without announcing it.
+    fn unseat(&mut self, id: PlayerID) {
+        let was_player = self.get_player_index(id).is_some();
+        self.state.players.retain(|p| p.id != id);
+        self.state.spectators.retain(|s| s.id != id);
+        self.state.muted.retain(|&m| m != id);
+        self.sessions.retain(|_, s| s.player_id != id);
+        if self.state.takeback_request == Some(id) {
+            self.state.takeback_request = None;
+        }
+        if was_player {
+            self.state.draw_offer = None;
+            self.state.rematch_offer = None;
+            self.restart_clock();
+        }
+    }
+
+    /// Name of the player or spectator the host wants to act on, after
+    /// checking that they may.
+    fn check_moderation(&self, host: PlayerID, target: PlayerID) -> Result<String, Error> {
+        if self.state.host().map(|p| p.id) != Some(host) {
+            return Err(Error::NotHost);
+        }
+        if target == host {
+            return Err(Error::InvalidPlayer);
+        }
+        self.state
+            .players
+            .iter()
+            .find(|p| p.id == target)
+            .map(|p| p.name.clone())
+            .or_else(|| {
+                self.state
+                    .spectators
+                    .iter()
+                    .find(|s| s.id == target)
+                    .map(|s| s.name.clone())
+            })
+            .ok_or(Error::InvalidPlayer)
+    }
+
+    /// Remove someone from the game, and with `ban`, stop them coming back.
+    fn kick(&mut self, host: PlayerID, target: PlayerID, ban: bool) -> Result<(), Error> {
+        let name = self.check_moderation(host, target)?;
+        // or a losing host could get out of the result
+        let seated = self.get_player_index(target).is_some();
+        if seated && self.state.winner.is_none() && !self.state.moves.is_empty() {
+            return Err(Error::GameInProgress);
+        }
+        if ban {
+            let sessions = self
+                .sessions
+                .iter()
+                .filter(|(_, s)| s.player_id == target)
+                .map(|(token, _)| token.clone())
+                .collect();
+            let profile_id = self
+                .state
+                .players
+                .iter()
+                .find(|p| p.id == target)
+                .and_then(|p| p.profile_id.clone());
+            self.bans.push(Ban {
+                player_id: target,
+                sessions,
+                profile_id,
+            });
+        }
+        self.add_chat_message(
+            ChatMessageSource::System,
+            format!(
+                "{} was {} by the host.",
+                name,
+                if ban { "banned" } else { "kicked" }
+            ),
+        );
+        self.unseat(target);
+        Ok(())
+    }
+
+    /// Returns whether anything changed.
+    fn set_muted(&mut self, host: PlayerID, target: PlayerID, muted: bool) -> Result<bool, Error> {
+        let name = self.check_moderation(host, target)?;
+        if self.state.muted.contains(&target) == muted {
+            return Ok(false);
+        }
+        if muted {
+            self.state.muted.push(target);
+        } else {
+            self.state.muted.retain(|&m| m != target);
+        }
+        self.add_chat_message(
+            ChatMessageSource::System,
+            format!(
+                "{} was {} by the host.",
+                name,
+                if muted { "muted" } else { "unmuted" }
+            ),
+        );
+        Ok(true)
+    }
+
+    fn is_banned(&self, session: Option<&str>, profile: Option<&Profile>) -> bool {
+        self.bans.iter().any(|ban| {
+            session.is_some_and(|token| ban.sessions.iter().any(|s| s == token))
+                || profile.is_some_and(|p| ban.profile_id.as_ref() == Some(&p.id))
+        })
+    }
+
+    /// Whether the player or spectator with this id was banned by the host,
+    /// rather than just kicked.
+    pub fn was_banned(&self, id: PlayerID) -> bool {
+        self.bans.iter().any(|ban| ban.player_id == id)
+    }
+
+    pub fn take_turn(&mut self, player_id: PlayerID, space: usize) -> Result<(), Error> {
+        if self.state.players.len() < 2 {
+            return Err(Error::NotEnoughPlayers);
+        }
+
+        // the clock may have run out before the server got round to checking
+        // it, and the move is too late then
+        if self.check_clock() {
+            self.broadcast_state();
+            return Err(Error::GameOver);
+        }
+
+        if self.state.winner.is_some() {
+            return Err(Error::GameOver);
+        }
+
+        let player_idx = match self.get_player_index(player_id) {
+            Some(idx) => idx,
+            None => return Err(Error::InvalidPlayer),
+        };
+        let team = self.state.players[player_idx].team;
+
+        if self.state.turn != self.state.players[player_idx].team {
+            return Err(Error::NotYourTurn);
+        }
+
+        self.state.play(space)?;
+        metrics::metrics().moves.inc();
+        if self.state.draw_offer.is_some_and(|id| id != player_id) {
+            self.state.draw_offer = None;
+        }
+        let now = unix_millis();
+        self.state.moves.push(MoveRecord {
+            player_id,
+            team,
+            space,
+            timestamp: now,
+        });
+        self.stop_clock(player_idx, now);
+
+        let (col, row) = self.state.coordinates(space);
+        self.add_chat_message(
+            ChatMessageSource::Player(player_id),
+            format!("Played {} at ({}, {}).", team, col, row),
+        );
+
+        self.record_result();
+        self.restart_clock();
+
+        Ok(())
+    }
+
+    /// Count the win, if the game has just ended, and announce the result.
+    /// Rated games are also reported for the players' ratings to be updated.
+    fn record_result(&mut self) {
+        if let (Some(_), Some(first)) = (&self.state.winner, self.state.moves.first()) {
+            let duration_ms = unix_millis().saturating_sub(first.timestamp);
+            metrics::metrics()
+                .game_duration
+                .observe(duration_ms as f64 / 1000.0);
+        }
+
+        if let (Some(winner), Some((x, o)), Some(results)) = (
+            &self.state.winner,
+            self.state.rated_profiles(),
+            &self.results,
+        ) {
+            let _ = results.send(GameResult {
+                game_id: self.id.clone(),
+                x,
+                o,
+                winner: match winner {
+                    EndState::Win(team) => Some(*team),
+                    EndState::Draw => None,
+                },
+                moves: self.state.moves.len(),
+                finished_at: unix_millis(),
+            });
+        }
+
+        match self.state.winner {
+            Some(EndState::Win(winning_team)) => {
+                let winner_idx = self.get_player_index_by_team(winning_team).unwrap();
+                self.state.players[winner_idx].wins += 1;
                 self.add_chat_message(
                     ChatMessageSource::System,
-                    "Players have swapped sides.".to_string(),
+                    format!("{} wins!", self.state.players[winner_idx]),
                 );
-                self.reset();
-                self.swap_teams();
+            }
+            Some(EndState::Draw) => {
+                self.add_chat_message(ChatMessageSource::System, "It's a draw!".to_string());
+            }
+            None => {}
+        }
+    }
+
+    /// Charge the time the player took for their move to their clock.
+    fn stop_clock(&mut self, player_idx: usize, now: u64) {
+        let (time_control, started_at) = match (self.state.time_control, self.state.turn_started_at)
+        {
+            (Some(tc), Some(started_at)) => (tc, started_at),
+            _ => return,
+        };
+        let player = &mut self.state.players[player_idx];
+        let remaining = player
+            .clock_ms
+            .unwrap_or(0)
+            .saturating_sub(now.saturating_sub(started_at));
+        player.clock_ms = Some(match time_control {
+            TimeControl::PerMove { move_ms } => move_ms,
+            TimeControl::Game { increment_ms, .. } => remaining + increment_ms,
+        });
+    }
+
+    /// Start the clock of the side to move, or stop the clocks if there is
+    /// no game in progress. Clocks don't run until both players have arrived
+    /// in their reserved seats, so that a late arrival is a no-show rather
+    /// than a loss on time.
+    fn restart_clock(&mut self) {
+        let running = self.state.time_control.is_some()
+            && self.state.players.len() == 2
+            && self.state.winner.is_none()
+            && !self
+                .state
+                .players
+                .iter()
+                .any(|p| self.awaiting_arrival(p.id));
+        self.state.turn_started_at = if running { Some(unix_millis()) } else { None };
+    }
+
+    fn reset_clocks(&mut self) {
+        let initial = self.state.time_control.map(|tc| tc.initial_ms());
+        self.state
+            .players
+            .iter_mut()
+            .for_each(|p| p.clock_ms = initial);
+        self.restart_clock();
+    }
+
+    /// End the game if the side to move has run out of time, returning true
+    /// if it did. Called by the server when the clock is due to run out.
+    pub fn check_clock(&mut self) -> bool {
+        let deadline = match self.state.clock_deadline() {
+            Some(deadline) => deadline,
+            None => return false,
+        };
+        if unix_millis() < deadline {
+            return false;
+        }
+
+        let flagged_team = self.state.turn;
+        let winning_team = if flagged_team == 'X' { 'O' } else { 'X' };
+        if let Some(idx) = self.get_player_index_by_team(flagged_team) {
+            self.state.players[idx].clock_ms = Some(0);
+            let text = format!("{} ran out of time.", self.state.players[idx]);
+            self.add_chat_message(ChatMessageSource::System, text);
+        }
+        self.end_game(EndState::Win(winning_team), EndReason::Timeout);
+        true
+    }
+
+    /// End the game other than by a move, then count and announce the result
+    /// the same way.
+    fn end_game(&mut self, winner: EndState, reason: EndReason) {
+        self.state.winner = Some(winner);
+        self.state.end_reason = Some(reason);
+        self.state.draw_offer = None;
+        self.record_result();
+        self.restart_clock();
+    }
+
+    /// The player's opponent wins.
+    fn resign(&mut self, player_id: PlayerID) -> Result<(), Error> {
+        self.check_game_in_progress()?;
+        let idx = self
+            .get_player_index(player_id)
+            .ok_or(Error::InvalidPlayer)?;
+        let team = self.state.players[idx].team;
+        let text = format!("{} resigned.", self.state.players[idx]);
+        self.add_chat_message(ChatMessageSource::System, text);
+        let winning_team = if team == 'X' { 'O' } else { 'X' };
+        self.end_game(EndState::Win(winning_team), EndReason::Resignation);
+        Ok(())
+    }
+
+    /// Offer the opponent a draw, or accept theirs if they already offered
+    /// one.
+    fn offer_draw(&mut self, player_id: PlayerID) -> Result<(), Error> {
+        self.check_game_in_progress()?;
+        match self.state.draw_offer {
+            Some(id) if id == player_id => return Err(Error::DrawPending),
+            Some(_) => return self.answer_draw(player_id, true),
+            None => {}
+        }
+        self.state.draw_offer = Some(player_id);
+        self.add_chat_message(ChatMessageSource::Player(player_id), "Draw?".to_string());
+        Ok(())
+    }
+
+    fn answer_draw(&mut self, player_id: PlayerID, accept: bool) -> Result<(), Error> {
+        match self.state.draw_offer {
+            Some(id) if id != player_id => {}
+            _ => return Err(Error::NoDrawOffered),
+        }
+        self.state.draw_offer = None;
+
+        if !accept {
+            self.add_chat_message(
+                ChatMessageSource::Player(player_id),
+                "No draw, let's play on.".to_string(),
+            );
+            return Ok(());
+        }
+        self.add_chat_message(
+            ChatMessageSource::Player(player_id),
+            "Agreed, it's a draw.".to_string(),
+        );
+        self.end_game(EndState::Draw, EndReason::Agreement);
+        Ok(())
+    }
+
+    fn check_game_in_progress(&self) -> Result<(), Error> {
+        if self.state.players.len() < 2 {
+            return Err(Error::NotEnoughPlayers);
+        }
+        if self.state.winner.is_some() {
+            return Err(Error::GameOver);
+        }
+        Ok(())
+    }
+
+    /// Only moves can be taken back, so not a game that ended any other way,
+    /// such as a resignation or on time. Ratings have already been updated
+    /// once a rated game is over, and tournaments have recorded the result.
+    fn check_takeback_allowed(&self) -> Result<(), Error> {
+        if self.state.tournament.is_some() {
+            return Err(Error::TournamentGame);
+        }
+        if self.state.winner.is_none() {
+            return Ok(());
+        }
+        if self.state.end_reason != Some(EndReason::Line) {
+            return Err(Error::GameOver);
+        }
+        if self.state.rated_profiles().is_some() {
+            return Err(Error::RatedGameFinished);
+        }
+        Ok(())
+    }
+
+    /// End the game because players didn't show up. The win goes to the
+    /// player given, or the game is drawn if nobody showed up.
+    pub fn forfeit(&mut self, winner: Option<PlayerID>) {
+        if self.state.winner.is_some() {
+            return;
+        }
+        let team = winner
+            .and_then(|id| self.get_player_index(id))
+            .map(|idx| self.state.players[idx].team);
+        match team {
+            Some(team) => {
+                let absent = self.state.players.iter().find(|p| p.team != team);
+                if let Some(absent) = absent {
+                    let text = format!("{} didn't show up.", absent);
+                    self.add_chat_message(ChatMessageSource::System, text);
+                }
+                self.end_game(EndState::Win(team), EndReason::Forfeit);
+            }
+            None => {
+                self.add_chat_message(ChatMessageSource::System, "Nobody showed up.".to_string());
+                self.end_game(EndState::Draw, EndReason::Forfeit);
+            }
+        }
+    }
+
+    fn request_takeback(&mut self, player_id: PlayerID) -> Result<(), Error> {
+        self.check_takeback_allowed()?;
+        if self.state.takeback_request.is_some() {
+            return Err(Error::TakebackPending);
+        }
+        if !self.state.moves.iter().any(|m| m.player_id == player_id) {
+            return Err(Error::NoMoveToTakeBack);
+        }
+        self.state.takeback_request = Some(player_id);
+        self.add_chat_message(
+            ChatMessageSource::Player(player_id),
+            "Can I take that back?".to_string(),
+        );
+        Ok(())
+    }
+
+    /// Answer the opponent's takeback request. Accepting undoes the
+    /// requester's last move, and the opponent's reply to it if there is one.
+    fn answer_takeback(&mut self, player_id: PlayerID, accept: bool) -> Result<(), Error> {
+        let requester = match self.state.takeback_request {
+            Some(id) if id != player_id => id,
+            _ => return Err(Error::NoTakebackRequested),
+        };
+        if accept {
+            self.check_takeback_allowed()?;
+        }
+        self.state.takeback_request = None;
+
+        if !accept {
+            self.add_chat_message(
+                ChatMessageSource::Player(player_id),
+                "No takebacks!".to_string(),
+            );
+            return Ok(());
+        }
+
+        let undo_from = match self
+            .state
+            .moves
+            .iter()
+            .rposition(|m| m.player_id == requester)
+        {
+            Some(idx) => idx,
+            None => return Err(Error::NoMoveToTakeBack),
+        };
+        let count = self.state.moves.len() - undo_from;
+        self.undo_moves(count);
+        self.add_chat_message(
+            ChatMessageSource::System,
+            format!(
+                "Took back {} move{}.",
+                count,
+                if count == 1 { "" } else { "s" }
+            ),
+        );
+        Ok(())
+    }
+
+    /// Roll the board back by replaying all but the last `count` moves. If the
+    /// game had been won, the win no longer counts.
+    fn undo_moves(&mut self, count: usize) {
+        if let Some(EndState::Win(team)) = self.state.winner {
+            if let Some(idx) = self.get_player_index_by_team(team) {
+                self.state.players[idx].wins -= 1;
             }
         }
+
+        let mut moves = std::mem::take(&mut self.state.moves);
+        moves.truncate(moves.len().saturating_sub(count));
+        self.state.clear_board();
+        for m in &moves {
+            // these were all legal the first time around
+            let _ = self.state.play(m.space);
+        }
+        self.state.moves = moves;
+        self.restart_clock();
+    }
+
+    pub fn broadcast_state(&mut self) {
+        self.state.version += 1;
+        self.state_changes.send_replace(self.state.clone());
+    }
+
+    /// Play again on an empty board, with the players swapping sides.
+    pub fn rematch(&mut self) {
+        self.add_chat_message(
+            ChatMessageSource::System,
+            "Players have swapped sides.".to_string(),
+        );
+        self.state.rematch_offer = None;
+        self.reset();
+        self.swap_teams();
+    }
+
+    /// Offer the opponent a rematch, or accept theirs if they already offered
+    /// one. Offered before the game is over, a rematch abandons it.
+    fn offer_rematch(&mut self, player_id: PlayerID) -> Result<(), Error> {
+        match &self.state.rematch_offer {
+            Some(offer) if offer.player_id == player_id => return Err(Error::RematchPending),
+            Some(_) => return self.answer_rematch(player_id, true),
+            None => {}
+        }
+        if self.state.players.len() < 2 {
+            return Err(Error::NotEnoughPlayers);
+        }
+        self.state.rematch_offer = Some(RematchOffer {
+            player_id,
+            expires_at: unix_millis() + REMATCH_OFFER_MS,
+        });
+        let text = if self.state.winner.is_some() {
+            "Rematch?"
+        } else {
+            "Shall we abandon this game and start again?"
+        };
+        self.add_chat_message(ChatMessageSource::Player(player_id), text.to_string());
+        Ok(())
+    }
+
+    fn answer_rematch(&mut self, player_id: PlayerID, accept: bool) -> Result<(), Error> {
+        match &self.state.rematch_offer {
+            Some(offer) if offer.player_id != player_id => {}
+            _ => return Err(Error::NoRematchOffered),
+        }
+        self.state.rematch_offer = None;
+
+        if !accept {
+            self.add_chat_message(
+                ChatMessageSource::Player(player_id),
+                "No rematch.".to_string(),
+            );
+            return Ok(());
+        }
+        self.add_chat_message(ChatMessageSource::Player(player_id), "Rematch!".to_string());
+        if self.state.winner.is_none() {
+            self.add_chat_message(
+                ChatMessageSource::System,
+                "The game was abandoned.".to_string(),
+            );
+        }
+        self.rematch();
+        Ok(())
+    }
+
+    /// Withdraw a rematch offer nobody answered in time, returning true if
+    /// there was one. Called by the server when the offer is due to lapse.
+    pub fn expire_rematch_offer(&mut self) -> bool {
+        match &self.state.rematch_offer {
+            Some(offer) if unix_millis() >= offer.expires_at => {}
+            _ => return false,
+        }
+        self.state.rematch_offer = None;
+        self.add_chat_message(
+            ChatMessageSource::System,
+            "The rematch offer has expired.".to_string(),
+        );
+        true
+    }
+
+    fn reset(&mut self) {
+        self.state.clear_board();
+        self.reset_clocks();
+    }
+    fn swap_teams(&mut self) {
+        self.state.players.iter_mut().for_each(|p| {
+            if p.team == 'X' {
+                p.team = 'O';
+            } else {
+                p.team = 'X';
+            }
+        });
+    }
+
+    pub fn handle_msg(&mut self, player_id: PlayerID, msg: FromBrowser) -> Result<bool, Error> {
+        debug!("Game: Handle Msg: {:?}", msg);
+        if !self.state.is_present(player_id) {
+            return Err(Error::InvalidPlayer);
+        }
+        let spectator = self.is_spectator(player_id);
+        match msg {
+            FromBrowser::ChatMsg { .. } | FromBrowser::ChangeName { .. }
+                if self.state.muted.contains(&player_id) =>
+            {
+                return Err(Error::Muted);
+            }
+            FromBrowser::ChatMsg { .. } if spectator && !self.state.spectator_chat => {
+                return Err(Error::SpectatorChatDisabled);
+            }
+            FromBrowser::Move { .. }
+            | FromBrowser::Resign
+            | FromBrowser::OfferDraw
+            | FromBrowser::AcceptDraw
+            | FromBrowser::DeclineDraw
+            | FromBrowser::Rematch
+            | FromBrowser::AcceptRematch
+            | FromBrowser::DeclineRematch
+            | FromBrowser::AddBot { .. }
+            | FromBrowser::RequestTakeback
+            | FromBrowser::AcceptTakeback
+            | FromBrowser::DeclineTakeback
+            | FromBrowser::SetPrivate { .. }
+                if spectator =>
+            {
+                return Err(Error::SpectatorsCanOnlyWatch);
+            }
+            FromBrowser::Rematch
+            | FromBrowser::AcceptRematch
+            | FromBrowser::DeclineRematch
+            | FromBrowser::AddBot { .. }
+            | FromBrowser::RequestTakeback
+            | FromBrowser::AcceptTakeback
+            | FromBrowser::DeclineTakeback
+            | FromBrowser::SetPrivate { .. }
+            | FromBrowser::Kick { .. }
+            | FromBrowser::Ban { .. }
+                if self.state.tournament.is_some() =>
+            {
+                return Err(Error::TournamentGame);
+            }
+            FromBrowser::ChatMsg { text } => {
+                let trimmed = text.trim();
+                if trimmed.is_empty() {
+                    return Err(Error::EmptyMessage);
+                }
+                if trimmed.len() > MAX_CHAT_LENGTH {
+                    return Err(Error::MessageTooLong);
+                }
+                let Some(text) = self.filter_text(trimmed)? else {
+                    return Ok(false);
+                };
+                self.add_chat_message(self.chat_source(player_id), text);
+            }
+            FromBrowser::ChangeName { new_name } => {
+                let Some(name) = self.filter_text(clip_name(&new_name))? else {
+                    return Ok(false);
+                };
+                self.update_player_name(player_id, name.clone()).unwrap();
+                self.add_chat_message(
+                    self.chat_source(player_id),
+                    format!("Now my name is \"{}\"!", name),
+                );
+            }
+            FromBrowser::Move { space } => self.take_turn(player_id, space)?,
+            FromBrowser::Resign => self.resign(player_id)?,
+            FromBrowser::OfferDraw => self.offer_draw(player_id)?,
+            FromBrowser::AcceptDraw => self.answer_draw(player_id, true)?,
+            FromBrowser::DeclineDraw => self.answer_draw(player_id, false)?,
+            FromBrowser::AddBot { .. } => {
+                // needs to spawn the bot's task, so is handled by server::Connection
+                return Err(Error::BotUnavailable);
+            }
+            // answered by the socket, since only the client asking needs them
+            FromBrowser::Resync | FromBrowser::FetchChat { .. } => return Ok(false),
+            FromBrowser::Kick { player_id: target } => self.kick(player_id, target, false)?,
+            FromBrowser::Ban { player_id: target } => self.kick(player_id, target, true)?,
+            FromBrowser::Mute { player_id: target } => {
+                return self.set_muted(player_id, target, true);
+            }
+            FromBrowser::Unmute { player_id: target } => {
+                return self.set_muted(player_id, target, false);
+            }
+            FromBrowser::RequestTakeback => self.request_takeback(player_id)?,
+            FromBrowser::AcceptTakeback => self.answer_takeback(player_id, true)?,
+            FromBrowser::DeclineTakeback => self.answer_takeback(player_id, false)?,
+            FromBrowser::SetPrivate { private } => {
+                if self.state.host().map(|p| p.id) != Some(player_id) {
+                    return Err(Error::NotHost);
+                }
+                if self.state.private == private {
+                    return Ok(false);
+                }
+                self.state.private = private;
+                self.add_chat_message(
+                    ChatMessageSource::System,
+                    if private {
+                        "The game is now private.".to_string()
+                    } else {
+                        "The game is now listed in the lobby.".to_string()
+                    },
+                );
+            }
+            FromBrowser::Rematch => self.offer_rematch(player_id)?,
+            FromBrowser::AcceptRematch => self.answer_rematch(player_id, true)?,
+            FromBrowser::DeclineRematch => self.answer_rematch(player_id, false)?,
+        }
         Ok(true)
     }
 }
 
+/// Version of `FromBrowser` and `ToBrowser`, sent in `JoinedGame`. Bumped
+/// whenever a change would break existing clients.
+///
+/// 2: `Error` has a `code` alongside the `message`.
+/// 3: `Rematch` offers one, which the opponent has to accept.
+pub const PROTOCOL_VERSION: u32 = 3;
+
 #[derive(Debug, Clone, Deserialize)]
 pub enum FromBrowser {
-    ChatMsg { text: String },
-    ChangeName { new_name: String },
-    Move { space: usize },
+    ChatMsg {
+        text: String,
+    },
+    ChangeName {
+        new_name: String,
+    },
+    Move {
+        space: usize,
+    },
+    Resign,
+    /// Offer a draw, or accept the opponent's offer.
+    OfferDraw,
+    AcceptDraw,
+    DeclineDraw,
+    /// Offer a rematch, or accept the opponent's offer.
     Rematch,
+    AcceptRematch,
+    DeclineRematch,
+    AddBot {
+        strategy: bot::Strategy,
+    },
+    /// Ask the opponent to let you undo your last move.
+    RequestTakeback,
+    AcceptTakeback,
+    DeclineTakeback,
+    /// Hide the game from the lobby, or list it again. Host only.
+    SetPrivate {
+        private: bool,
+    },
+    /// Ask for the whole `GameState`, after receiving a `Patch` whose `from`
+    /// isn't the version the client has.
+    Resync,
+    /// Ask for the chat messages before the one with id `before`, which are
+    /// sent back in a `ChatPage`.
+    FetchChat {
+        before: usize,
+    },
+    /// Remove a player or spectator from the game. Host only.
+    Kick {
+        player_id: PlayerID,
+    },
+    /// Remove a player or spectator, and stop them rejoining with the same
+    /// session or profile. Host only.
+    Ban {
+        player_id: PlayerID,
+    },
+    /// Stop a player or spectator chatting or changing their name. Host only.
+    Mute {
+        player_id: PlayerID,
+    },
+    Unmute {
+        player_id: PlayerID,
+    },
+}
+
+impl FromBrowser {
+    /// Name of the variant, for metrics and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FromBrowser::ChatMsg { .. } => "ChatMsg",
+            FromBrowser::ChangeName { .. } => "ChangeName",
+            FromBrowser::Move { .. } => "Move",
+            FromBrowser::Resign => "Resign",
+            FromBrowser::OfferDraw => "OfferDraw",
+            FromBrowser::AcceptDraw => "AcceptDraw",
+            FromBrowser::DeclineDraw => "DeclineDraw",
+            FromBrowser::Rematch => "Rematch",
+            FromBrowser::AcceptRematch => "AcceptRematch",
+            FromBrowser::DeclineRematch => "DeclineRematch",
+            FromBrowser::AddBot { .. } => "AddBot",
+            FromBrowser::RequestTakeback => "RequestTakeback",
+            FromBrowser::AcceptTakeback => "AcceptTakeback",
+            FromBrowser::DeclineTakeback => "DeclineTakeback",
+            FromBrowser::SetPrivate { .. } => "SetPrivate",
+            FromBrowser::Resync => "Resync",
+            FromBrowser::FetchChat { .. } => "FetchChat",
+            FromBrowser::Kick { .. } => "Kick",
+            FromBrowser::Ban { .. } => "Ban",
+            FromBrowser::Mute { .. } => "Mute",
+            FromBrowser::Unmute { .. } => "Unmute",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub enum ToBrowser {
     JoinedGame {
+        /// See `PROTOCOL_VERSION`.
+        protocol_version: u32,
         token: String,
         player_id: PlayerID,
+        role: Role,
+        /// Pass back as the `session` param to reconnect to the same place.
+        session: String,
         state: State,
     },
     GameState(State),
-    Error(String),
+    /// Changes since the state with version `from`, bringing the client up to
+    /// `version`. Only sent to clients that asked for patches; they should
+    /// send `Resync` if they don't have version `from`.
+    Patch {
+        from: u64,
+        version: u64,
+        changes: Vec<patch::Change>,
+    },
+    /// Games with a free seat, newest first. Sent to lobby subscribers.
+    Lobby(Vec<LobbyGame>),
+    /// Sent to tournament feed subscribers whenever the tournament changes.
+    /// `seat` is where the subscribed entrant plays their current match.
+    Tournament {
+        tournament: tournament::State,
+        seat: Option<tournament::Seat>,
+    },
+    /// Place in the matchmaking queue, starting from 1.
+    QueuePosition(usize),
+    /// Reply to `FetchChat`, oldest first. `more` is whether there are older
+    /// messages still.
+    ChatPage {
+        messages: Vec<ChatMessage>,
+        more: bool,
+    },
+    Error {
+        code: Error,
+        message: String,
+    },
+}
+
+impl ToBrowser {
+    pub fn error(code: Error, message: impl Into<String>) -> ToBrowser {
+        ToBrowser::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for ToBrowser {
+    fn from(e: Error) -> ToBrowser {
+        ToBrowser::error(e, e.to_string())
+    }
+}
+
+/// Why a message from the browser was refused, or couldn't be understood.
+/// Sent as the `code` of `ToBrowser::Error`, so clients can act on it without
+/// matching the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Error {
+    /// A text frame that couldn't be parsed as JSON.
+    InvalidJson,
+    /// A binary frame that isn't valid MessagePack or CBOR, whichever the
+    /// client asked for.
+    InvalidEncoding,
+    /// Well-formed, but not a `FromBrowser` message: an unknown variant, or
+    /// missing or mistyped fields.
+    InvalidMessage,
+    /// A binary frame with JSON, or a text frame with a binary encoding. The
+    /// socket is closed after this.
+    UnsupportedData,
+    NotEnoughPlayers,
+    GameOver,
+    NotYourTurn,
+    SpaceOutOfRange,
+    SpaceOccupied,
+    /// Ultimate games only: the move must be in the sub-board the last move
+    /// sent the player to.
+    WrongSubBoard,
+    /// Ultimate games only: that sub-board has already been won or drawn.
+    SubBoardFinished,
+    GameFull,
+    InvalidPlayer,
+    SpectatorsCanOnlyWatch,
+    SpectatorChatDisabled,
+    EmptyMessage,
+    MessageTooLong,
+    /// The chat filter found a listed word in a strict game.
+    Profanity,
+    /// The chat filter found a link in a strict game.
+    LinksNotAllowed,
+    /// Rematches, bots, takebacks and privacy are up to the tournament.
+    TournamentGame,
+    NotHost,
+    BotUnavailable,
+    TakebackPending,
+    NoTakebackRequested,
+    NoMoveToTakeBack,
+    /// Rated games can't be taken back once the ratings have been updated.
+    RatedGameFinished,
+    DrawPending,
+    NoDrawOffered,
+    RematchPending,
+    NoRematchOffered,
+    /// Matchmaking only: there's no game to send messages to yet.
+    WaitingForOpponent,
+    /// The host has muted the player in chat.
+    Muted,
+    /// The host can't remove their opponent once the game has started,
+    /// until it's over.
+    GameInProgress,
+    /// The host removed the player from the game. The socket is closed after
+    /// this.
+    Kicked,
+    /// The host banned the player from the game, so they can't rejoin.
+    Banned,
+    /// Something went wrong on the server, and the details were logged.
+    Internal,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            Error::InvalidJson => "Invalid JSON",
+            Error::InvalidEncoding => "Message couldn't be decoded",
+            Error::InvalidMessage => "Invalid message",
+            Error::UnsupportedData => "Frames must be text for JSON, and binary otherwise",
+            Error::NotEnoughPlayers => "Not enough players",
+            Error::GameOver => "Game is over",
+            Error::NotYourTurn => "Not your turn",
+            Error::SpaceOutOfRange => "There's no such space",
+            Error::SpaceOccupied => "That space is taken",
+            Error::WrongSubBoard => "You have to play in the highlighted board",
+            Error::SubBoardFinished => "That board is already decided",
+            Error::GameFull => "Game is full",
+            Error::InvalidPlayer => "Invalid player ID",
+            Error::SpectatorsCanOnlyWatch => "Spectators can only watch",
+            Error::SpectatorChatDisabled => "Spectators can't chat in this game",
+            Error::EmptyMessage => "Empty message",
+            Error::MessageTooLong => "Message too long",
+            Error::Profanity => "Please keep it friendly",
+            Error::LinksNotAllowed => "Links aren't allowed in this game",
+            Error::TournamentGame => "Not allowed in tournament games",
+            Error::NotHost => "Only the host can change that",
+            Error::BotUnavailable => "Unable to add a computer player",
+            Error::TakebackPending => "A takeback has already been requested",
+            Error::NoTakebackRequested => "No takeback to answer",
+            Error::NoMoveToTakeBack => "No move to take back",
+            Error::RatedGameFinished => "Finished rated games can't be taken back",
+            Error::DrawPending => "A draw has already been offered",
+            Error::NoDrawOffered => "No draw to answer",
+            Error::RematchPending => "A rematch has already been offered",
+            Error::NoRematchOffered => "No rematch to answer",
+            Error::WaitingForOpponent => "Still waiting for an opponent",
+            Error::Muted => "You have been muted",
+            Error::GameInProgress => "Players can't be removed during a game",
+            Error::Kicked => "You were removed from the game by the host",
+            Error::Banned => "You are banned from this game",
+            Error::Internal => "Something went wrong",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Random letters and digits, for ids and secrets.
+pub fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// A name without surrounding whitespace and cut to `MAX_NAME_LENGTH`, or
+/// "Unnamed Player" if that leaves nothing.
+pub fn clip_name(name: &str) -> &str {
+    let name = name.trim();
+    if name.is_empty() {
+        return "Unnamed Player";
+    }
+    name.char_indices()
+        .nth(MAX_NAME_LENGTH)
+        .map_or(name, |(end, _)| &name[..end])
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `size` square board with X on `xs` and O on `os`.
+    fn marked(size: usize, xs: &[usize], os: &[usize]) -> Vec<char> {
+        let mut board = vec![' '; size * size];
+        xs.iter().for_each(|&s| board[s] = 'X');
+        os.iter().for_each(|&s| board[s] = 'O');
+        board
+    }
+
+    /// Whether every mark of a line completes it, whichever was played last.
+    fn line_from_anywhere(size: usize, win_length: usize, line: &[usize]) -> bool {
+        let board = marked(size, line, &[]);
+        line.iter()
+            .all(|&space| completes_line(&board, size, win_length, space))
+    }
+
+    #[test]
+    fn lines_are_found_in_every_direction() {
+        for line in [
+            [0, 1, 2],
+            [6, 7, 8],
+            [0, 3, 6],
+            [2, 5, 8],
+            [0, 4, 8],
+            [2, 4, 6],
+        ] {
+            assert!(line_from_anywhere(3, 3, &line), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn lines_dont_wrap_around_the_edges() {
+        // the end of one row and the start of the next
+        assert!(!line_from_anywhere(3, 3, &[1, 2, 3]));
+        assert!(!line_from_anywhere(4, 3, &[3, 4, 5]));
+        // diagonals off the side of the board
+        assert!(!line_from_anywhere(4, 3, &[2, 7, 8]));
+        assert!(!line_from_anywhere(4, 3, &[1, 4, 7]));
+    }
+
+    #[test]
+    fn lines_must_be_long_enough_and_unbroken() {
+        let board = marked(3, &[0, 1], &[2]);
+        assert!(!completes_line(&board, 3, 3, 0));
+        assert!(!completes_line(&board, 3, 3, 2));
+        assert!(!completes_line(&board, 3, 3, 4));
+
+        let board = marked(5, &[0, 1, 3, 4], &[2]);
+        assert!(!completes_line(&board, 5, 3, 0));
+        assert!(!completes_line(&board, 5, 3, 4));
+    }
+
+    #[test]
+    fn shorter_lines_win_on_bigger_boards() {
+        // five in a row needs three
+        assert!(line_from_anywhere(5, 3, &[6, 12, 18]));
+        assert!(line_from_anywhere(5, 3, &[8, 12, 16]));
+        assert!(line_from_anywhere(5, 3, &[22, 23, 24]));
+        assert!(line_from_anywhere(5, 3, &[4, 9, 14]));
+        assert!(!line_from_anywhere(5, 3, &[12, 18]));
+        // a longer line than needed still wins
+        assert!(line_from_anywhere(7, 4, &[14, 15, 16, 17, 18]));
+        assert!(!line_from_anywhere(7, 4, &[14, 15, 16]));
+    }
+
+    #[test]
+    fn long_names_are_cut_at_a_character_boundary() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let player = game.add_player("Player".to_string()).unwrap();
+        let new_name = "漢字".repeat(20);
+        game.handle_msg(player.id, FromBrowser::ChangeName { new_name })
+            .unwrap();
+        assert_eq!(game.state.players[0].name, "漢字".repeat(16));
+    }
+
+    /// A game between X and O where X has completed the top row.
+    fn won_by_x() -> (Game, Player, Player) {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = game.add_player("X".to_string()).unwrap();
+        let o = game.add_player("O".to_string()).unwrap();
+        for (player, space) in [(&x, 0), (&o, 3), (&x, 1), (&o, 4), (&x, 2)] {
+            game.take_turn(player.id, space).unwrap();
+        }
+        (game, x, o)
+    }
+
+    #[test]
+    fn taking_back_a_winning_move_takes_back_the_win() {
+        let (mut game, x, o) = won_by_x();
+        assert_eq!(game.state.players[0].wins, 1);
+        game.handle_msg(x.id, FromBrowser::RequestTakeback).unwrap();
+        game.handle_msg(o.id, FromBrowser::AcceptTakeback).unwrap();
+
+        assert_eq!(game.state.winner, None);
+        assert_eq!(game.state.end_reason, None);
+        assert_eq!(game.state.players[0].wins, 0);
+        assert_eq!(game.state.moves.len(), 4);
+        assert_eq!(game.state.board[2], ' ');
+        assert_eq!(game.state.turn, 'X');
+    }
+
+    #[test]
+    fn taking_back_undoes_the_reply_too() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = game.add_player("X".to_string()).unwrap();
+        let o = game.add_player("O".to_string()).unwrap();
+        game.take_turn(x.id, 0).unwrap();
+        game.take_turn(o.id, 4).unwrap();
+        assert_eq!(
+            game.handle_msg(o.id, FromBrowser::AcceptTakeback),
+            Err(Error::NoTakebackRequested)
+        );
+        game.handle_msg(x.id, FromBrowser::RequestTakeback).unwrap();
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::RequestTakeback),
+            Err(Error::TakebackPending)
+        );
+        game.handle_msg(o.id, FromBrowser::AcceptTakeback).unwrap();
+        assert!(game.state.moves.is_empty());
+        assert!(game.state.board.iter().all(|&c| c == ' '));
+    }
+
+    #[test]
+    fn games_that_ended_without_a_line_cant_be_taken_back() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = game.add_player("X".to_string()).unwrap();
+        let o = game.add_player("O".to_string()).unwrap();
+        game.take_turn(x.id, 0).unwrap();
+        game.handle_msg(o.id, FromBrowser::Resign).unwrap();
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::RequestTakeback),
+            Err(Error::GameOver)
+        );
+
+        let options = Options {
+            time_control: Some(TimeControl::PerMove { move_ms: 60_000 }),
+            ..Options::default()
+        };
+        let (mut game, _rx) = Game::new("test".to_string(), options);
+        let x = game.add_player("X".to_string()).unwrap();
+        let o = game.add_player("O".to_string()).unwrap();
+        game.take_turn(x.id, 0).unwrap();
+        game.state.turn_started_at = Some(unix_millis() - 61_000);
+        assert!(game.check_clock());
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::RequestTakeback),
+            Err(Error::GameOver)
+        );
+        assert_eq!(game.state.players[1].clock_ms, Some(0));
+        assert_eq!(game.get_player_index(o.id), Some(1));
+    }
+
+    #[test]
+    fn tournament_games_cant_be_taken_back() {
+        let (mut game, x, o) = won_by_x();
+        game.state.tournament = Some("cup".to_string());
+        assert_eq!(game.request_takeback(x.id), Err(Error::TournamentGame));
+        game.state.takeback_request = Some(x.id);
+        assert_eq!(game.answer_takeback(o.id, true), Err(Error::TournamentGame));
+        assert_eq!(game.state.winner, Some(EndState::Win('X')));
+    }
+
+    #[test]
+    fn moves_after_the_clock_runs_out_are_refused() {
+        let options = Options {
+            time_control: Some(TimeControl::PerMove { move_ms: 60_000 }),
+            ..Options::default()
+        };
+        let (mut game, _rx) = Game::new("test".to_string(), options);
+        let x = game.add_player("X".to_string()).unwrap();
+        game.add_player("O".to_string()).unwrap();
+        game.state.turn_started_at = Some(unix_millis() - 61_000);
+
+        assert_eq!(game.take_turn(x.id, 0), Err(Error::GameOver));
+        assert!(matches!(game.state.winner, Some(EndState::Win('O'))));
+        assert_eq!(game.state.end_reason, Some(EndReason::Timeout));
+        assert_eq!(game.state.board[0], ' ');
+    }
+
+    #[test]
+    fn snapshots_from_before_later_fields_can_be_restored() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        game.add_player("Player".to_string()).unwrap();
+        let mut saved = serde_json::to_value(game.snapshot()).unwrap();
+        let state = saved["state"].as_object_mut().unwrap();
+        for field in ["moves", "created_at", "private"] {
+            state.remove(field);
+        }
+
+        let snapshot: Snapshot = serde_json::from_value(saved).unwrap();
+        assert!(snapshot.state.moves.is_empty());
+        assert!(!snapshot.state.private);
+    }
+
+    #[test]
+    fn the_host_cant_kick_their_opponent_mid_game() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let host = game.add_player("Host".to_string()).unwrap();
+        let opponent = game.add_player("Opponent".to_string()).unwrap();
+        game.handle_msg(host.id, FromBrowser::Move { space: 0 })
+            .unwrap();
+        let kick = FromBrowser::Kick {
+            player_id: opponent.id,
+        };
+        assert_eq!(
+            game.handle_msg(host.id, kick.clone()),
+            Err(Error::GameInProgress)
+        );
+
+        game.handle_msg(host.id, FromBrowser::Resign).unwrap();
+        game.handle_msg(host.id, kick).unwrap();
+        assert!(game.get_player_index(opponent.id).is_none());
+    }
+
+    #[test]
+    fn clocks_wait_for_reserved_players_to_arrive() {
+        let options = Options {
+            time_control: Some(TimeControl::PerMove { move_ms: 60_000 }),
+            ..Options::default()
+        };
+        let (mut game, _rx) = Game::new("test".to_string(), options);
+        let (_, x_session) = game.reserve_seat("X".to_string()).unwrap();
+        let (_, o_session) = game.reserve_seat("O".to_string()).unwrap();
+        assert_eq!(game.state.turn_started_at, None);
+
+        game.join("X".to_string(), Some(&x_session), None).unwrap();
+        assert_eq!(game.state.turn_started_at, None);
+        game.join("O".to_string(), Some(&o_session), None).unwrap();
+        assert!(game.state.turn_started_at.is_some());
+    }
+
+    fn play_all(state: &mut State, spaces: &[usize]) {
+        for &space in spaces {
+            state.play(space).unwrap();
+        }
+    }
+
+    #[test]
+    fn ultimate_moves_are_sent_to_the_matching_sub_board() {
+        let mut state = State::new(Options::ultimate());
+        // the first move can go anywhere, the centre cell of sub-board 4
+        // sends the reply back there
+        play_all(&mut state, &[4 * 9 + 4]);
+        let ultimate = state.ultimate.as_ref().unwrap();
+        assert_eq!(ultimate.next_sub_board, Some(4));
+
+        assert_eq!(state.play(0), Err(Error::WrongSubBoard));
+        play_all(&mut state, &[4 * 9 + 2]);
+        assert_eq!(state.ultimate.as_ref().unwrap().next_sub_board, Some(2));
+        assert_eq!(state.coordinates(4 * 9 + 2), (6, 4));
+    }
+
+    #[test]
+    fn being_sent_to_a_finished_sub_board_frees_the_move() {
+        let mut state = State::new(Options::ultimate());
+        // O takes the middle row of sub-board 0, then X sends O back there
+        play_all(&mut state, &[0, 3, 27, 4, 36, 5]);
+        let ultimate = state.ultimate.as_ref().unwrap();
+        assert_eq!(ultimate.sub_boards[0], Some(EndState::Win('O')));
+        assert_eq!(ultimate.next_sub_board, Some(5));
+
+        play_all(&mut state, &[45]);
+        assert_eq!(state.ultimate.as_ref().unwrap().next_sub_board, None);
+        assert_eq!(state.play(6), Err(Error::SubBoardFinished));
+        play_all(&mut state, &[80]);
+        assert_eq!(state.winner, None);
+    }
+
+    #[test]
+    fn three_sub_boards_in_a_row_win_ultimate_games() {
+        let mut state = State::new(Options::ultimate());
+        let ultimate = state.ultimate.as_mut().unwrap();
+        ultimate.sub_boards[0] = Some(EndState::Win('X'));
+        ultimate.sub_boards[1] = Some(EndState::Win('X'));
+        ultimate.next_sub_board = Some(2);
+        state.board[18] = 'X';
+        state.board[19] = 'X';
+
+        play_all(&mut state, &[20]);
+        assert_eq!(
+            state.ultimate.as_ref().unwrap().sub_boards[2],
+            Some(EndState::Win('X'))
+        );
+        assert_eq!(state.winner, Some(EndState::Win('X')));
+        assert_eq!(state.end_reason, Some(EndReason::Line));
+    }
+
+    fn joined(game: &mut Game, name: &str) -> Joined {
+        game.join(name.to_string(), None, None).unwrap()
+    }
+
+    #[test]
+    fn reconnecting_takes_the_held_seat_back() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = joined(&mut game, "X");
+        joined(&mut game, "O");
+
+        assert!(game.disconnect(x.player_id, x.connection_id));
+        assert!(!game.state.players[0].connected);
+
+        let again = game
+            .join("Someone else".to_string(), Some(&x.session), None)
+            .unwrap();
+        assert_eq!(again.player_id, x.player_id);
+        assert_eq!(again.role, Role::Player);
+        assert_ne!(again.connection_id, x.connection_id);
+        assert!(game.state.players[0].connected);
+        assert_eq!(game.state.players[0].name, "X");
+
+        // the old connection's grace period ending changes nothing
+        game.expire_seat(x.player_id, x.connection_id);
+        assert_eq!(game.state.players.len(), 2);
+        assert!(!game.disconnect(x.player_id, x.connection_id));
+        assert!(game.state.players[0].connected);
+    }
+
+    #[test]
+    fn seats_are_given_up_when_the_player_doesnt_return() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = joined(&mut game, "X");
+        joined(&mut game, "O");
+
+        assert!(game.disconnect(x.player_id, x.connection_id));
+        game.expire_seat(x.player_id, x.connection_id);
+        assert!(game.get_player_index(x.player_id).is_none());
+
+        // the session went with the seat, so it only gets a new place
+        let again = game.join("X".to_string(), Some(&x.session), None).unwrap();
+        assert_ne!(again.player_id, x.player_id);
+        assert_ne!(again.session, x.session);
+    }
+
+    #[test]
+    fn spectators_leave_as_soon_as_they_disconnect() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        joined(&mut game, "X");
+        joined(&mut game, "O");
+        let watcher = joined(&mut game, "Watcher");
+        assert_eq!(watcher.role, Role::Spectator);
+
+        assert!(!game.disconnect(watcher.player_id, watcher.connection_id));
+        assert!(!game.is_spectator(watcher.player_id));
+    }
+
+    fn team_of(game: &Game, player: &Player) -> char {
+        let idx = game.get_player_index(player.id).unwrap();
+        game.state.players[idx].team
+    }
+
+    #[test]
+    fn accepted_rematches_swap_sides() {
+        let (mut game, x, o) = won_by_x();
+        game.handle_msg(x.id, FromBrowser::Rematch).unwrap();
+        assert_eq!(game.state.rematch_offer.as_ref().unwrap().player_id, x.id);
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::Rematch),
+            Err(Error::RematchPending)
+        );
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::AcceptRematch),
+            Err(Error::NoRematchOffered)
+        );
+        assert_eq!(game.state.board[0], 'X');
+
+        game.handle_msg(o.id, FromBrowser::AcceptRematch).unwrap();
+        assert_eq!(game.state.rematch_offer, None);
+        assert_eq!(game.state.winner, None);
+        assert!(game.state.board.iter().all(|&c| c == ' '));
+        assert_eq!((team_of(&game, &x), team_of(&game, &o)), ('O', 'X'));
+        assert_eq!(game.state.players[0].wins, 1);
+    }
+
+    #[test]
+    fn offering_a_rematch_back_accepts_it() {
+        let (mut game, x, o) = won_by_x();
+        game.handle_msg(o.id, FromBrowser::Rematch).unwrap();
+        game.handle_msg(x.id, FromBrowser::Rematch).unwrap();
+        assert_eq!(game.state.rematch_offer, None);
+        assert_eq!(team_of(&game, &x), 'O');
+    }
+
+    #[test]
+    fn declined_rematches_leave_the_board_alone() {
+        let (mut game, x, o) = won_by_x();
+        game.handle_msg(x.id, FromBrowser::Rematch).unwrap();
+        game.handle_msg(o.id, FromBrowser::DeclineRematch).unwrap();
+        assert_eq!(game.state.rematch_offer, None);
+        assert_eq!(game.state.winner, Some(EndState::Win('X')));
+        assert_eq!(team_of(&game, &x), 'X');
+    }
+
+    #[test]
+    fn rematch_offers_lapse() {
+        let (mut game, x, o) = won_by_x();
+        game.handle_msg(x.id, FromBrowser::Rematch).unwrap();
+        assert!(!game.expire_rematch_offer());
+        assert!(game.state.rematch_offer.is_some());
+
+        let expires_at = unix_millis() - 1;
+        game.state.rematch_offer.as_mut().unwrap().expires_at = expires_at;
+        assert_eq!(game.state.next_deadline(), Some(expires_at));
+        assert!(game.expire_rematch_offer());
+        assert_eq!(game.state.rematch_offer, None);
+        assert_eq!(
+            game.handle_msg(o.id, FromBrowser::AcceptRematch),
+            Err(Error::NoRematchOffered)
+        );
+    }
+
+    #[test]
+    fn rematches_agreed_mid_game_abandon_it() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = game.add_player("X".to_string()).unwrap();
+        let o = game.add_player("O".to_string()).unwrap();
+        game.take_turn(x.id, 4).unwrap();
+        game.handle_msg(o.id, FromBrowser::Rematch).unwrap();
+        assert_eq!(game.state.board[4], 'X');
+
+        game.handle_msg(x.id, FromBrowser::AcceptRematch).unwrap();
+        assert!(game.state.moves.is_empty());
+        assert_eq!((team_of(&game, &x), team_of(&game, &o)), ('O', 'X'));
+        assert!(game.state.players.iter().all(|p| p.wins == 0));
+    }
+
+    fn two_players() -> (Game, Player, Player) {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = game.add_player("X".to_string()).unwrap();
+        let o = game.add_player("O".to_string()).unwrap();
+        (game, x, o)
+    }
+
+    #[test]
+    fn resigning_hands_the_win_to_the_opponent() {
+        let (mut game, x, o) = two_players();
+        game.take_turn(x.id, 4).unwrap();
+        game.handle_msg(o.id, FromBrowser::Resign).unwrap();
+        assert_eq!(game.state.winner, Some(EndState::Win('X')));
+        assert_eq!(game.state.end_reason, Some(EndReason::Resignation));
+        assert_eq!(game.state.players[0].wins, 1);
+
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::Resign),
+            Err(Error::GameOver)
+        );
+        assert_eq!(game.take_turn(o.id, 0), Err(Error::GameOver));
+    }
+
+    #[test]
+    fn draws_have_to_be_agreed() {
+        let (mut game, x, o) = two_players();
+        game.handle_msg(x.id, FromBrowser::OfferDraw).unwrap();
+        assert_eq!(game.state.draw_offer, Some(x.id));
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::OfferDraw),
+            Err(Error::DrawPending)
+        );
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::AcceptDraw),
+            Err(Error::NoDrawOffered)
+        );
+
+        game.handle_msg(o.id, FromBrowser::AcceptDraw).unwrap();
+        assert_eq!(game.state.winner, Some(EndState::Draw));
+        assert_eq!(game.state.end_reason, Some(EndReason::Agreement));
+        assert_eq!(game.state.draw_offer, None);
+        assert!(game.state.players.iter().all(|p| p.wins == 0));
+    }
+
+    #[test]
+    fn declined_or_ignored_draw_offers_play_on() {
+        let (mut game, x, o) = two_players();
+        game.handle_msg(x.id, FromBrowser::OfferDraw).unwrap();
+        game.handle_msg(o.id, FromBrowser::DeclineDraw).unwrap();
+        assert_eq!(game.state.draw_offer, None);
+        assert_eq!(game.state.winner, None);
+
+        // making a move instead of answering turns the offer down
+        game.take_turn(x.id, 4).unwrap();
+        game.handle_msg(x.id, FromBrowser::OfferDraw).unwrap();
+        game.take_turn(o.id, 0).unwrap();
+        assert_eq!(game.state.draw_offer, None);
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::AcceptDraw),
+            Err(Error::NoDrawOffered)
+        );
+    }
+
+    #[test]
+    fn resigning_and_draws_need_an_opponent() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = game.add_player("X".to_string()).unwrap();
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::Resign),
+            Err(Error::NotEnoughPlayers)
+        );
+        assert_eq!(
+            game.handle_msg(x.id, FromBrowser::OfferDraw),
+            Err(Error::NotEnoughPlayers)
+        );
+    }
+
+    /// X and O have joined and X has played in the centre.
+    fn under_way() -> (Game, Joined, Joined) {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let x = joined(&mut game, "X");
+        let o = joined(&mut game, "O");
+        game.take_turn(x.player_id, 4).unwrap();
+        (game, x, o)
+    }
+
+    #[test]
+    fn walking_out_mid_game_forfeits_it() {
+        let (mut game, x, o) = under_way();
+        assert!(game.disconnect(o.player_id, o.connection_id));
+        assert_eq!(game.state.winner, None);
+
+        game.expire_seat(o.player_id, o.connection_id);
+        assert_eq!(game.state.winner, Some(EndState::Win('X')));
+        assert_eq!(game.state.end_reason, Some(EndReason::Abandonment));
+        assert_eq!(game.state.players.len(), 1);
+        assert_eq!(game.state.players[0].wins, 1);
+
+        // whoever takes the empty seat starts a new game
+        let newcomer = joined(&mut game, "Newcomer");
+        assert_eq!(newcomer.role, Role::Player);
+        assert_eq!(game.state.winner, None);
+        assert!(game.state.moves.is_empty());
+        assert!(game.state.board.iter().all(|&c| c == ' '));
+        assert!(game.get_player_index(x.player_id).is_some());
+    }
+
+    #[test]
+    fn leaving_before_the_first_move_isnt_a_forfeit() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        joined(&mut game, "X");
+        let o = joined(&mut game, "O");
+        game.disconnect(o.player_id, o.connection_id);
+        game.expire_seat(o.player_id, o.connection_id);
+        assert_eq!(game.state.winner, None);
+        assert_eq!(game.state.end_reason, None);
+    }
+
+    #[test]
+    fn nobody_wins_when_both_players_walk_out() {
+        let (mut game, x, o) = under_way();
+        game.disconnect(x.player_id, x.connection_id);
+        game.disconnect(o.player_id, o.connection_id);
+        game.expire_seat(o.player_id, o.connection_id);
+        assert_eq!(game.state.winner, None);
+        assert_eq!(game.state.board[4], 'X');
+    }
+
+    #[test]
+    fn finished_games_arent_forfeited() {
+        let (mut game, x, o) = under_way();
+        game.handle_msg(x.player_id, FromBrowser::Resign).unwrap();
+        game.disconnect(x.player_id, x.connection_id);
+        game.expire_seat(x.player_id, x.connection_id);
+        assert_eq!(game.state.winner, Some(EndState::Win('O')));
+        assert_eq!(game.state.end_reason, Some(EndReason::Resignation));
+        assert!(game.get_player_index(o.player_id).is_some());
+    }
 }
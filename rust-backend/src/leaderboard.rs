@@ -0,0 +1,295 @@
+// Rankings of players, computed from the results of rated games
+use crate::game::unix_millis;
+use crate::rating::RecordedResult;
+use crate::storage::ProfileStore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    /// Rating after the player's last game in the window.
+    #[default]
+    Rating,
+    Wins,
+    /// Longest run of consecutive wins within the window.
+    Streak,
+    Games,
+}
+
+/// Which games count: those finished in the last day, the last week, or ever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    Daily,
+    Weekly,
+    #[default]
+    All,
+}
+
+impl Window {
+    /// Start of the window, in milliseconds since the Unix epoch.
+    pub fn since(&self, now: u64) -> u64 {
+        const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+        match self {
+            Window::Daily => now.saturating_sub(DAY_MS),
+            Window::Weekly => now.saturating_sub(7 * DAY_MS),
+            Window::All => 0,
+        }
+    }
+}
+
+/// One player's record within the window.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    /// Position in the whole leaderboard, starting from 1.
+    pub rank: usize,
+    pub profile_id: String,
+    pub name: String,
+    pub rating: i32,
+    pub games: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    pub best_streak: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Leaderboard {
+    pub sort: Sort,
+    pub window: Window,
+    pub page: usize,
+    pub per_page: usize,
+    /// Number of players with a game in the window.
+    pub total: usize,
+    pub entries: Vec<Entry>,
+}
+
+/// Most entries on one page.
+pub const MAX_PER_PAGE: usize = 100;
+
+/// Build a page of the leaderboard from the stored results. Blocks on the
+/// store, so call from a blocking task.
+pub fn load(
+    profiles: &dyn ProfileStore,
+    sort: Sort,
+    window: Window,
+    page: usize,
+    per_page: usize,
+) -> Result<Leaderboard, String> {
+    let page = page.max(1);
+    let per_page = per_page.clamp(1, MAX_PER_PAGE);
+    let results = profiles.results_since(window.since(unix_millis()))?;
+    let (mut entries, total) = rank(&results, sort, page, per_page);
+    for entry in entries.iter_mut() {
+        if let Some(profile) = profiles.profile(&entry.profile_id)? {
+            entry.name = profile.name;
+        }
+    }
+
+    Ok(Leaderboard {
+        sort,
+        window,
+        page,
+        per_page,
+        total,
+        entries,
+    })
+}
+
+#[derive(Default)]
+struct Record {
+    rating: i32,
+    games: u32,
+    wins: u32,
+    losses: u32,
+    draws: u32,
+    streak: u32,
+    best_streak: u32,
+}
+
+/// Rank every player in `results`, which must be oldest first, and return
+/// the `page` (starting from 1) of `per_page` entries along with the total
+/// number of players. Entries are returned without names.
+fn rank(
+    results: &[RecordedResult],
+    sort: Sort,
+    page: usize,
+    per_page: usize,
+) -> (Vec<Entry>, usize) {
+    let mut records: HashMap<&str, Record> = HashMap::new();
+    for recorded in results {
+        let result = &recorded.result;
+        for (profile_id, team, rating) in [
+            (&result.x, 'X', recorded.x_rating),
+            (&result.o, 'O', recorded.o_rating),
+        ] {
+            let record = records.entry(profile_id).or_default();
+            record.rating = rating;
+            record.games += 1;
+            match result.winner {
+                Some(winner) if winner == team => {
+                    record.wins += 1;
+                    record.streak += 1;
+                    record.best_streak = record.best_streak.max(record.streak);
+                }
+                Some(_) => {
+                    record.losses += 1;
+                    record.streak = 0;
+                }
+                None => {
+                    record.draws += 1;
+                    record.streak = 0;
+                }
+            }
+        }
+    }
+
+    let key = |r: &Record| match sort {
+        Sort::Rating => r.rating as i64,
+        Sort::Wins => r.wins as i64,
+        Sort::Streak => r.best_streak as i64,
+        Sort::Games => r.games as i64,
+    };
+    let mut ranked: Vec<(&str, Record)> = records.into_iter().collect();
+    ranked.sort_by(|(a_id, a), (b_id, b)| {
+        key(b)
+            .cmp(&key(a))
+            .then_with(|| b.rating.cmp(&a.rating))
+            .then_with(|| a_id.cmp(b_id))
+    });
+
+    let total = ranked.len();
+    let entries = ranked
+        .into_iter()
+        .enumerate()
+        .skip(page.saturating_sub(1).saturating_mul(per_page))
+        .take(per_page)
+        .map(|(i, (profile_id, r))| Entry {
+            rank: i + 1,
+            profile_id: profile_id.to_string(),
+            name: String::new(),
+            rating: r.rating,
+            games: r.games,
+            wins: r.wins,
+            losses: r.losses,
+            draws: r.draws,
+            best_streak: r.best_streak,
+        })
+        .collect();
+    (entries, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rating::{self, GameResult, Profile};
+    use crate::storage::MemoryStore;
+
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+
+    fn recorded(x: &str, o: &str, winner: Option<char>, finished_at: u64) -> RecordedResult {
+        RecordedResult {
+            result: GameResult {
+                game_id: "game".to_string(),
+                x: x.to_string(),
+                o: o.to_string(),
+                winner,
+                moves: 5,
+                finished_at,
+            },
+            x_rating: rating::INITIAL_RATING,
+            o_rating: rating::INITIAL_RATING,
+        }
+    }
+
+    fn result(x: &Profile, o: &Profile, finished_at: u64) -> GameResult {
+        GameResult {
+            game_id: "game".to_string(),
+            x: x.id.clone(),
+            o: o.id.clone(),
+            winner: Some('X'),
+            moves: 5,
+            finished_at,
+        }
+    }
+
+    fn ids(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.profile_id.as_str()).collect()
+    }
+
+    #[test]
+    fn windows_start_a_day_or_a_week_ago() {
+        let now = 30 * 24 * HOUR_MS;
+        assert_eq!(Window::Daily.since(now), now - 24 * HOUR_MS);
+        assert_eq!(Window::Weekly.since(now), now - 7 * 24 * HOUR_MS);
+        assert_eq!(Window::All.since(now), 0);
+        assert_eq!(Window::Weekly.since(HOUR_MS), 0);
+    }
+
+    #[test]
+    fn only_games_in_the_window_count() {
+        let store = MemoryStore::new();
+        let a = store.device_profile("a-key", "A").unwrap();
+        let b = store.device_profile("b-key", "B").unwrap();
+        let c = store.device_profile("c-key", "C").unwrap();
+        let now = unix_millis();
+        for (x, o, finished_at) in [
+            (&a, &b, now - 3 * 24 * HOUR_MS),
+            (&c, &b, now - 8 * 24 * HOUR_MS),
+            (&a, &b, now - HOUR_MS),
+        ] {
+            store.record_result(&result(x, o, finished_at)).unwrap();
+        }
+
+        let daily = load(&store, Sort::Games, Window::Daily, 1, 10).unwrap();
+        assert_eq!(daily.total, 2);
+        assert_eq!(daily.entries[0].games, 1);
+
+        let weekly = load(&store, Sort::Games, Window::Weekly, 1, 10).unwrap();
+        assert_eq!(weekly.total, 2);
+        assert_eq!(weekly.entries[0].games, 2);
+
+        let all = load(&store, Sort::Wins, Window::All, 1, 10).unwrap();
+        assert_eq!(all.total, 3);
+        assert_eq!(all.entries[0].name, "A");
+        assert_eq!((all.entries[0].wins, all.entries[0].games), (2, 2));
+    }
+
+    #[test]
+    fn streaks_are_broken_by_losses_and_draws() {
+        let results = [
+            recorded("a", "b", Some('X'), 1),
+            recorded("a", "b", Some('X'), 2),
+            recorded("a", "b", None, 3),
+            recorded("a", "b", Some('X'), 4),
+            recorded("b", "a", Some('X'), 5),
+            recorded("b", "a", Some('X'), 6),
+            recorded("b", "a", Some('X'), 7),
+        ];
+        let (entries, total) = rank(&results, Sort::Streak, 1, 10);
+        assert_eq!(total, 2);
+        assert_eq!(ids(&entries), ["b", "a"]);
+        assert_eq!((entries[0].best_streak, entries[1].best_streak), (3, 2));
+        assert_eq!(entries[1].wins, 3);
+        assert_eq!(entries[1].draws, 1);
+    }
+
+    #[test]
+    fn pages_keep_their_rank() {
+        let results: Vec<RecordedResult> = (0..5)
+            .map(|i| {
+                let mut r = recorded(&format!("p{}", i), "loser", Some('X'), i);
+                r.x_rating = 1300 + i as i32;
+                r
+            })
+            .collect();
+        let (entries, total) = rank(&results, Sort::Rating, 2, 2);
+        assert_eq!(total, 6);
+        assert_eq!(ids(&entries), ["p2", "p1"]);
+        assert_eq!(entries[0].rank, 3);
+
+        let (entries, _) = rank(&results, Sort::Rating, 4, 2);
+        assert!(entries.is_empty());
+    }
+}
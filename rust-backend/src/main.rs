@@ -1,22 +1,36 @@
+mod bot;
+mod chat_filter;
+mod encoding;
+mod events;
 mod game;
+mod leaderboard;
+mod matchmaking;
+mod metrics;
+mod patch;
+mod rating;
 mod server;
+mod socket;
+mod storage;
+mod tournament;
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Query, State,
+        ws::{WebSocket, WebSocketUpgrade},
+        Path, Query, State,
     },
     http::StatusCode,
-    response::{IntoResponse, Redirect, Response},
-    routing::{get, MethodFilter},
-    Router,
+    response::{sse::Sse, IntoResponse, Redirect, Response},
+    routing::{get, post, MethodFilter},
+    Json, Router,
 };
+use chat_filter::{Strictness, Verdict};
+use encoding::Encoding;
 use serde::Deserialize;
+use socket::{GameSocket, SocketError, Transport};
 use std::sync::Arc;
 use tokio::time::{sleep, Duration};
 use tower_http::trace::TraceLayer;
-use tracing::debug;
-use tracing_subscriber;
+use tracing::{debug, error, info};
 
 #[tokio::main]
 async fn main() {
@@ -26,7 +40,34 @@ async fn main() {
         std::env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:5173/".to_string());
     let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
 
-    let shared_state = Arc::new(server::State::new(frontend_url.clone()));
+    // one store keeps both games and profiles
+    let (store, profiles): (Arc<dyn storage::GameStore>, Arc<dyn storage::ProfileStore>) =
+        match std::env::var("DATABASE_PATH") {
+            Ok(path) => {
+                let store = Arc::new(storage::SqliteStore::open(&path).expect("open database"));
+                (store.clone(), store)
+            }
+            Err(_) => {
+                let store = Arc::new(storage::MemoryStore::new());
+                (store.clone(), store)
+            }
+        };
+
+    let chat_filter = match std::env::var("CHAT_FILTER_WORDS") {
+        Ok(path) => chat_filter::Filter::load(&path).expect("load chat filter words"),
+        Err(_) => chat_filter::Filter::default(),
+    };
+
+    let shared_state = Arc::new(server::State::new(
+        frontend_url.clone(),
+        store,
+        profiles,
+        chat_filter,
+    ));
+    match server::restore_games(&shared_state) {
+        Ok(count) => info!("Restored {} saved games", count),
+        Err(e) => error!("Failed to restore saved games: {}", e),
+    }
 
     let app = Router::new()
         .route(
@@ -34,7 +75,36 @@ async fn main() {
             get(redirect_to_frontend).on(MethodFilter::OPTIONS, cors_options),
         )
         .route("/ws", get(open_conn))
+        .route(
+            "/events",
+            get(open_event_stream)
+                .post(post_message)
+                .on(MethodFilter::OPTIONS, cors_options),
+        )
+        .route("/matchmake", get(open_matchmaking_conn))
+        .route(
+            "/games",
+            get(list_games).on(MethodFilter::OPTIONS, cors_options),
+        )
+        .route("/lobby", get(open_lobby_conn))
+        .route("/players/:id", get(get_player))
+        .route("/leaderboard", get(get_leaderboard))
+        .route(
+            "/tournaments",
+            post(create_tournament).on(MethodFilter::OPTIONS, cors_options),
+        )
+        .route("/tournaments/:id", get(get_tournament))
+        .route(
+            "/tournaments/:id/entrants",
+            post(register_entrant).on(MethodFilter::OPTIONS, cors_options),
+        )
+        .route(
+            "/tournaments/:id/start",
+            post(start_tournament).on(MethodFilter::OPTIONS, cors_options),
+        )
+        .route("/tournaments/:id/feed", get(open_tournament_feed))
         .route("/health", get(|| async { (StatusCode::OK, "OK\n") }))
+        .route("/metrics", get(get_metrics))
         .route("/robots.txt", get(robots_txt))
         .fallback(get(redirect_to_frontend))
         .with_state(shared_state)
@@ -50,7 +120,7 @@ async fn main() {
 }
 
 async fn redirect_to_frontend(State(state): State<Arc<server::State>>) -> Redirect {
-    Redirect::temporary(&state.frontend_url.as_str())
+    Redirect::temporary(state.frontend_url.as_str())
 }
 
 async fn cors_options(State(state): State<Arc<server::State>>) -> impl IntoResponse {
@@ -58,13 +128,302 @@ async fn cors_options(State(state): State<Arc<server::State>>) -> impl IntoRespo
         StatusCode::NO_CONTENT,
         [
             ("Access-Control-Allow-Origin", state.frontend_url.clone()),
-            ("Access-Control-Allow-Methods", String::from("GET, OPTIONS")),
+            (
+                "Access-Control-Allow-Methods",
+                String::from("GET, POST, OPTIONS"),
+            ),
             ("Access-Control-Allow-Headers", String::from("Content-Type")),
             ("Access-Control-Max-Age", String::from("3600")),
         ],
     )
 }
 
+/// Games with a free seat, newest first.
+async fn list_games(State(state): State<Arc<server::State>>) -> impl IntoResponse {
+    (
+        [("Access-Control-Allow-Origin", state.frontend_url.clone())],
+        Json(state.lobby_games()),
+    )
+}
+
+async fn get_player(Path(id): Path<String>, State(state): State<Arc<server::State>>) -> Response {
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    let profile = tokio::task::spawn_blocking(move || state.profiles.profile(&id)).await;
+    match profile {
+        Ok(Ok(Some(profile))) => (cors, Json(profile)).into_response(),
+        Ok(Ok(None)) => (StatusCode::NOT_FOUND, cors, "Player not found").into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("Failed to load profile: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, cors, "Error").into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, cors, "Error").into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaderboardParams {
+    #[serde(default)]
+    pub sort: leaderboard::Sort,
+    #[serde(default)]
+    pub window: leaderboard::Window,
+    /// Starting from 1.
+    #[serde(default)]
+    pub page: Option<usize>,
+    #[serde(default)]
+    pub per_page: Option<usize>,
+}
+
+/// Top players by rating, wins, win streak or games played, from the results
+/// of rated games.
+async fn get_leaderboard(
+    Query(params): Query<LeaderboardParams>,
+    State(state): State<Arc<server::State>>,
+) -> Response {
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    let board = tokio::task::spawn_blocking(move || {
+        leaderboard::load(
+            state.profiles.as_ref(),
+            params.sort,
+            params.window,
+            params.page.unwrap_or(1),
+            params.per_page.unwrap_or(20),
+        )
+    })
+    .await;
+    match board {
+        Ok(Ok(board)) => (cors, Json(board)).into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("Failed to load leaderboard: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, cors, "Error").into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, cors, "Error").into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateTournamentParams {
+    pub name: String,
+    pub format: tournament::Format,
+    /// Time limit per move in every game, defaults to a minute so that
+    /// absent players can't hold up a round.
+    #[serde(default)]
+    pub move_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterEntrantParams {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StartTournamentParams {
+    pub host_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TournamentFeedParams {
+    /// Entrant key, to be sent the entrant's seat in each match.
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+fn find_tournament(
+    state: &server::State,
+    id: &str,
+) -> Option<Arc<std::sync::Mutex<tournament::Tournament>>> {
+    state.tournaments.read().unwrap().get(id).cloned()
+}
+
+/// Create a tournament, returning its id and the host key needed to start it.
+async fn create_tournament(
+    State(state): State<Arc<server::State>>,
+    Json(params): Json<CreateTournamentParams>,
+) -> Response {
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    let options = game::Options {
+        time_control: Some(game::TimeControl::PerMove {
+            move_ms: params.move_seconds.unwrap_or(60).saturating_mul(1000),
+        }),
+        ..game::Options::default()
+    };
+    let name = params.name.trim().chars().take(64).collect();
+    match tournament::create(&state, name, params.format, options) {
+        Ok(tournament) => {
+            let tournament = tournament.lock().unwrap();
+            let body = serde_json::json!({
+                "id": tournament.id,
+                "host_key": tournament.host_key(),
+                "tournament": tournament.state,
+            });
+            (StatusCode::CREATED, cors, Json(body)).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, cors, e).into_response(),
+    }
+}
+
+/// Bracket, results and standings.
+async fn get_tournament(
+    Path(id): Path<String>,
+    State(state): State<Arc<server::State>>,
+) -> Response {
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    match find_tournament(&state, &id) {
+        Some(tournament) => {
+            let body = tournament.lock().unwrap().state.clone();
+            (cors, Json(body)).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, cors, "Tournament not found").into_response(),
+    }
+}
+
+/// Register for a tournament, returning the entrant id and the secret key to
+/// subscribe to the feed with.
+async fn register_entrant(
+    Path(id): Path<String>,
+    State(state): State<Arc<server::State>>,
+    Json(params): Json<RegisterEntrantParams>,
+) -> Response {
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    let tournament = match find_tournament(&state, &id) {
+        Some(tournament) => tournament,
+        None => return (StatusCode::NOT_FOUND, cors, "Tournament not found").into_response(),
+    };
+    let mut tournament = tournament.lock().unwrap();
+    match tournament.register(params.name) {
+        Ok((entrant_id, key)) => {
+            tournament.broadcast_state();
+            let body = serde_json::json!({ "entrant_id": entrant_id, "key": key });
+            (StatusCode::CREATED, cors, Json(body)).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, cors, e).into_response(),
+    }
+}
+
+async fn start_tournament(
+    Path(id): Path<String>,
+    State(state): State<Arc<server::State>>,
+    Json(params): Json<StartTournamentParams>,
+) -> Response {
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    let tournament = match find_tournament(&state, &id) {
+        Some(tournament) => tournament,
+        None => return (StatusCode::NOT_FOUND, cors, "Tournament not found").into_response(),
+    };
+    match tournament::start(&state, &tournament, &params.host_key) {
+        Ok(()) => {
+            let body = tournament.lock().unwrap().state.clone();
+            (cors, Json(body)).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, cors, e).into_response(),
+    }
+}
+
+async fn open_tournament_feed(
+    Path(id): Path<String>,
+    Query(params): Query<TournamentFeedParams>,
+    State(state): State<Arc<server::State>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let tournament = match find_tournament(&state, &id) {
+        Some(tournament) => tournament,
+        None => return (StatusCode::NOT_FOUND, "Tournament not found").into_response(),
+    };
+    let entrant = match params.key {
+        Some(key) => match tournament.lock().unwrap().entrant_by_key(&key) {
+            Some(entrant) => Some(entrant),
+            None => return (StatusCode::BAD_REQUEST, "Invalid parameters").into_response(),
+        },
+        None => None,
+    };
+
+    ws.on_upgrade(move |socket| handle_tournament_socket(socket, tournament, entrant))
+}
+
+/// Send the tournament, then again every time it changes, along with the
+/// entrant's seat in their current match if they subscribed with their key.
+async fn handle_tournament_socket(
+    mut socket: WebSocket,
+    tournament: Arc<std::sync::Mutex<tournament::Tournament>>,
+    entrant: Option<tournament::EntrantID>,
+) {
+    debug!("New tournament feed connection for entrant {:?}", entrant);
+    let _socket_guard = metrics::SocketGuard::new("tournament");
+    let result = follow_tournament(&mut socket, tournament, entrant).await;
+    socket::close(socket, result).await;
+}
+
+async fn follow_tournament(
+    socket: &mut WebSocket,
+    tournament: Arc<std::sync::Mutex<tournament::Tournament>>,
+    entrant: Option<tournament::EntrantID>,
+) -> Result<(), SocketError> {
+    let mut changes = tournament.lock().unwrap().state_changes.subscribe();
+
+    loop {
+        let msg = {
+            let unlocked = tournament.lock().unwrap();
+            game::ToBrowser::Tournament {
+                tournament: unlocked.state.clone(),
+                seat: entrant.and_then(|e| unlocked.seat_for(e)),
+            }
+        };
+        changes.borrow_and_update();
+        socket::send(socket, &msg).await?;
+
+        loop {
+            tokio::select! {
+                _ = sleep(Duration::from_secs(10)) => socket::ping(socket).await?,
+                changed = changes.changed() => match changed {
+                    Ok(()) => break,
+                    Err(_) => return Err(SocketError::Gone("Tournament closed")),
+                },
+                text = socket::recv(socket) => {
+                    if text?.is_none() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The profile belonging to a device key, if one was given. Profile names
+/// are public, so they're filtered like a standard game's chat.
+async fn load_profile(
+    state: &Arc<server::State>,
+    device_key: Option<String>,
+    name: Option<String>,
+) -> Result<Option<rating::Profile>, String> {
+    let device_key = match device_key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let state = state.clone();
+    let name = game::clip_name(name.as_deref().unwrap_or_default());
+    let name = match state.chat_filter.check(name, Strictness::Standard) {
+        Verdict::Allow(name) => name,
+        Verdict::Reject(_) | Verdict::Drop => "Unnamed Player".to_string(),
+    };
+    tokio::task::spawn_blocking(move || state.profiles.device_profile(&device_key, &name))
+        .await
+        .map_err(|e| e.to_string())?
+        .map(Some)
+}
+
+/// Device keys are secrets chosen by the client, which identify a player's
+/// profile across games.
+fn is_valid_device_key(key: &str) -> bool {
+    (16..=64).contains(&key.len()) && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+async fn get_metrics(State(state): State<Arc<server::State>>) -> impl IntoResponse {
+    server::update_gauges(&state);
+    (
+        [("Content-Type", prometheus::TEXT_FORMAT)],
+        metrics::metrics().encode(),
+    )
+}
+
 async fn robots_txt() -> (StatusCode, &'static str) {
     (StatusCode::OK, "User-agent: *\nDisallow: /\n")
 }
@@ -75,6 +434,49 @@ struct NewGameParams {
     pub token: Option<String>,
     #[serde(default)]
     pub name: Option<String>,
+    /// Session from a previous `JoinedGame`, to reconnect to the same seat.
+    #[serde(default)]
+    pub session: Option<String>,
+    /// "standard" or "ultimate", for a newly created game.
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Board size for a newly created game; ignored when joining.
+    #[serde(default)]
+    pub size: Option<usize>,
+    /// Marks in a row needed to win a newly created game.
+    #[serde(default)]
+    pub win_length: Option<usize>,
+    /// Whether spectators may chat in a newly created game, defaults to true.
+    #[serde(default)]
+    pub spectator_chat: Option<bool>,
+    /// How strictly chat is filtered in a newly created game.
+    #[serde(default)]
+    pub chat_filter: Option<chat_filter::Strictness>,
+    /// Time limit per move, in seconds.
+    #[serde(default)]
+    pub move_seconds: Option<u64>,
+    /// Time each player has for the whole game, in seconds.
+    #[serde(default)]
+    pub clock_seconds: Option<u64>,
+    /// Added to a player's clock after each move when `clock_seconds` is set.
+    #[serde(default)]
+    pub increment_seconds: Option<u64>,
+    /// Seat a computer opponent using this strategy, see `bot::Strategy`.
+    #[serde(default)]
+    pub bot: Option<String>,
+    /// Keep a newly created game out of the lobby.
+    #[serde(default)]
+    pub private: Option<bool>,
+    /// Secret identifying the player's profile, for rated games.
+    #[serde(default)]
+    pub device_key: Option<String>,
+    /// Send `Patch` updates instead of the whole `GameState` on every change.
+    #[serde(default)]
+    pub patches: Option<bool>,
+    /// How messages are encoded, JSON unless "msgpack" or "cbor" is asked
+    /// for. `/events` only supports JSON.
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
 }
 
 impl NewGameParams {
@@ -90,6 +492,89 @@ impl NewGameParams {
                 .clone()
                 .map(|s| s.trim().to_string())
                 .filter(|s| !s.is_empty()),
+            session: self
+                .session
+                .clone()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            mode: self
+                .mode
+                .clone()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            size: self.size,
+            win_length: self.win_length,
+            spectator_chat: self.spectator_chat,
+            chat_filter: self.chat_filter,
+            move_seconds: self.move_seconds,
+            clock_seconds: self.clock_seconds,
+            increment_seconds: self.increment_seconds,
+            bot: self
+                .bot
+                .clone()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            private: self.private,
+            patches: self.patches,
+            encoding: self.encoding,
+            device_key: self
+                .device_key
+                .clone()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+        }
+    }
+
+    pub fn bot_strategy(&self) -> Option<bot::Strategy> {
+        self.bot.as_ref().and_then(|s| s.parse().ok())
+    }
+
+    pub fn game_mode(&self) -> Option<game::Mode> {
+        self.mode.as_ref().and_then(|s| s.parse().ok())
+    }
+
+    pub fn time_control(&self) -> Option<game::TimeControl> {
+        match (self.move_seconds, self.clock_seconds) {
+            (Some(seconds), _) => Some(game::TimeControl::PerMove {
+                move_ms: seconds.saturating_mul(1000),
+            }),
+            (None, Some(seconds)) => Some(game::TimeControl::Game {
+                initial_ms: seconds.saturating_mul(1000),
+                increment_ms: self.increment_seconds.unwrap_or(0).saturating_mul(1000),
+            }),
+            (None, None) => None,
+        }
+    }
+
+    /// Game options requested by the client. The win length defaults to the
+    /// board size, capped at 5 (gomoku) for large boards. Ultimate games
+    /// ignore the board size and win length.
+    pub fn game_options(&self) -> game::Options {
+        let defaults = game::Options::default();
+        let spectator_chat = self.spectator_chat.unwrap_or(defaults.spectator_chat);
+        let time_control = self.time_control();
+        let private = self.private.unwrap_or(defaults.private);
+        let chat_filter = self.chat_filter.unwrap_or(defaults.chat_filter);
+
+        if self.game_mode() == Some(game::Mode::Ultimate) {
+            return game::Options {
+                spectator_chat,
+                time_control,
+                private,
+                chat_filter,
+                ..game::Options::ultimate()
+            };
+        }
+
+        let board_size = self.size.unwrap_or(defaults.board_size);
+        game::Options {
+            mode: game::Mode::Standard,
+            board_size,
+            win_length: self.win_length.unwrap_or(board_size.min(5)),
+            spectator_chat,
+            time_control,
+            private,
+            chat_filter,
         }
     }
 
@@ -99,7 +584,21 @@ impl NewGameParams {
                 return false;
             }
         }
-        return true;
+        if self.bot.is_some() && self.bot_strategy().is_none() {
+            return false;
+        }
+        if self.mode.is_some() && self.game_mode().is_none() {
+            return false;
+        }
+        if self.move_seconds.is_some() && self.clock_seconds.is_some() {
+            return false;
+        }
+        if let Some(key) = &self.device_key {
+            if !is_valid_device_key(key) {
+                return false;
+            }
+        }
+        self.game_options().validate().is_ok()
     }
 }
 
@@ -116,102 +615,361 @@ async fn open_conn(
     ws.on_upgrade(|socket| handle_socket(socket, params, state))
 }
 
-async fn handle_socket(mut socket: WebSocket, params: NewGameParams, state: Arc<server::State>) {
+async fn handle_socket(socket: WebSocket, params: NewGameParams, state: Arc<server::State>) {
     // let redis = state.redis_conn_mgr.clone();
     debug!("New WebSocket connection with params: '{:?}'", params);
 
-    let mut conn = match server::join_or_new_game(state.clone(), params.token, params.name) {
+    let socket = GameSocket::new(socket, params.encoding.unwrap_or_default());
+    let conn = match join(&params, &state).await {
         Ok(c) => c,
+        Err(e) => return socket.close(Err(SocketError::Refused(e))).await,
+    };
+    let patches = params.patches.unwrap_or(false);
+    play(socket, conn, params.bot_strategy(), patches).await;
+}
+
+/// Join the game asked for by a client of `/ws` or `/events`, or create it.
+async fn join(
+    params: &NewGameParams,
+    state: &Arc<server::State>,
+) -> Result<server::Connection, game::Error> {
+    let profile = load_profile(state, params.device_key.clone(), params.name.clone())
+        .await
+        .map_err(|e| {
+            error!("Failed to load profile: {}", e);
+            game::Error::Internal
+        })?;
+    let conn = server::join_or_new_game(
+        state.clone(),
+        params.token.clone(),
+        params.name.clone(),
+        params.session.clone(),
+        profile,
+        params.game_options(),
+    )?;
+    debug!(
+        "{:?} {} joined game '{}' (new game: {})",
+        conn.role, conn.player_id, conn.game_id, conn.is_new_game
+    );
+    Ok(conn)
+}
+
+/// Play over server-sent events, for clients that can't open a WebSocket.
+/// Takes the same parameters as `/ws`, and the same messages are sent down
+/// the stream. Messages from the client are POSTed to `/events` with the
+/// session from `JoinedGame`.
+async fn open_event_stream(
+    Query(params): Query<NewGameParams>,
+    State(state): State<Arc<server::State>>,
+) -> Response {
+    let params = params.normalized();
+    if !params.is_valid() || params.encoding.is_some_and(|e| e.is_binary()) {
+        return (StatusCode::BAD_REQUEST, "Invalid parameters").into_response();
+    }
+
+    let (events, stream) = events::channel();
+    tokio::spawn(handle_event_stream(events, params, state.clone()));
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    (cors, Sse::new(stream)).into_response()
+}
+
+async fn handle_event_stream(
+    events: tokio::sync::mpsc::Sender<axum::response::sse::Event>,
+    params: NewGameParams,
+    state: Arc<server::State>,
+) {
+    debug!("New event stream with params: '{:?}'", params);
+
+    let mut conn = match join(&params, &state).await {
+        Ok(c) => c,
+        Err(e) => return events::close(events, Err(SocketError::Refused(e))).await,
+    };
+    let _socket_guard = metrics::SocketGuard::new("events");
+    let mut stream = events::EventStream::new(events.clone(), state, conn.session.clone());
+    let patches = params.patches.unwrap_or(false);
+    let result = relay(&mut stream, &mut conn, params.bot_strategy(), patches).await;
+    drop(stream);
+    events::close(events, result).await;
+}
+
+#[derive(Debug, Deserialize)]
+struct PostMessageParams {
+    session: String,
+}
+
+/// A `FromBrowser` message from a client following `/events`. Any reply,
+/// including errors about the message, comes down the event stream.
+async fn post_message(
+    Query(params): Query<PostMessageParams>,
+    State(state): State<Arc<server::State>>,
+    body: String,
+) -> impl IntoResponse {
+    let cors = [("Access-Control-Allow-Origin", state.frontend_url.clone())];
+    let status = match events::deliver(&state, &params.session, body) {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(status) => status,
+    };
+    (status, cors)
+}
+
+#[derive(Debug, Deserialize)]
+struct MatchmakingParams {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Secret identifying the player's profile, for rated games.
+    #[serde(default)]
+    pub device_key: Option<String>,
+    /// See `NewGameParams::patches`.
+    #[serde(default)]
+    pub patches: Option<bool>,
+    /// See `NewGameParams::encoding`.
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+}
+
+async fn open_matchmaking_conn(
+    Query(params): Query<MatchmakingParams>,
+    State(state): State<Arc<server::State>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let name = params
+        .name
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+    let device_key = params
+        .device_key
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+    if device_key
+        .as_deref()
+        .is_some_and(|key| !is_valid_device_key(key))
+    {
+        return (StatusCode::BAD_REQUEST, "Invalid parameters").into_response();
+    }
+
+    let patches = params.patches.unwrap_or(false);
+    let socket_encoding = params.encoding.unwrap_or_default();
+    ws.on_upgrade(move |socket| {
+        let socket = GameSocket::new(socket, socket_encoding);
+        handle_matchmaking_socket(socket, name, device_key, patches, state)
+    })
+}
+
+/// Wait in the matchmaking queue until paired with another player, then play
+/// as normal. Closing the socket while waiting gives up the place in the queue.
+async fn handle_matchmaking_socket(
+    mut socket: GameSocket,
+    name: Option<String>,
+    device_key: Option<String>,
+    patches: bool,
+    state: Arc<server::State>,
+) {
+    debug!("New matchmaking connection for {:?}", name);
+
+    let profile = match load_profile(&state, device_key, name.clone()).await {
+        Ok(profile) => profile,
         Err(e) => {
-            let json = serde_json::to_string(&game::ToBrowser::Error(e)).unwrap();
-            socket.send(Message::Text(json)).await.unwrap();
-            socket.close().await.unwrap();
+            error!("Failed to load profile: {}", e);
+            let refused = SocketError::Refused(game::Error::Internal);
+            return socket.close(Err(refused)).await;
+        }
+    };
+    let ticket = match matchmaking::enter(&state, name, profile) {
+        Ok(matchmaking::Entry::Matched(conn)) => {
+            debug!("Matchmaking: joined game '{}'", conn.game_id);
+            return play(socket, conn, None, patches).await;
+        }
+        Ok(matchmaking::Entry::Waiting(ticket)) => ticket,
+        Err(e) => return socket.close(Err(SocketError::Refused(e))).await,
+    };
+
+    let queued = metrics::SocketGuard::new("matchmaking");
+    let waited = wait_for_opponent(&mut socket, ticket).await;
+    drop(queued);
+    let conn = match waited {
+        Ok(Some(conn)) => conn,
+        Ok(None) => {
+            debug!("Matchmaking: client left the queue");
             return;
         }
+        Err(e) => return socket.close(Err(e)).await,
     };
 
-    let json = serde_json::to_string(&game::ToBrowser::JoinedGame {
-        token: conn.game_id.clone(),
-        player_id: conn.player.id,
-        state: conn.game_state.borrow().clone(),
-    })
-    .unwrap();
-    socket.send(Message::Text(json)).await.unwrap();
+    debug!(
+        "Matchmaking: {:?} {} joined game '{}'",
+        conn.role, conn.player_id, conn.game_id
+    );
+    play(socket, conn, None, patches).await;
+}
+
+async fn open_lobby_conn(
+    State(state): State<Arc<server::State>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(|socket| handle_lobby_socket(socket, state))
+}
+
+/// Keep the client up to date with its place in the queue until an opponent
+/// arrives, or None if the client leaves. The ticket is given up either way.
+async fn wait_for_opponent(
+    socket: &mut GameSocket,
+    mut ticket: matchmaking::Ticket,
+) -> Result<Option<server::Connection>, SocketError> {
+    let position = *ticket.position.borrow();
+    socket
+        .send(&game::ToBrowser::QueuePosition(position))
+        .await?;
+
+    loop {
+        tokio::select! {
+            _ = sleep(Duration::from_secs(10)) => socket.keep_alive().await?,
+            matched = &mut ticket.matched => {
+                return match matched {
+                    Ok(conn) => Ok(Some(conn)),
+                    Err(_) => Err(SocketError::Gone("Dropped from the queue")),
+                };
+            }
+            Ok(_) = ticket.position.changed() => {
+                let position = *ticket.position.borrow();
+                socket.send(&game::ToBrowser::QueuePosition(position)).await?;
+            }
+            data = socket.recv() => {
+                if data?.is_none() {
+                    return Ok(None);
+                }
+                socket.send(&game::Error::WaitingForOpponent.into()).await?;
+            }
+        }
+    }
+}
+
+/// Send the list of open games, then again every time it changes.
+async fn handle_lobby_socket(mut socket: WebSocket, state: Arc<server::State>) {
+    debug!("New lobby connection");
+    let _socket_guard = metrics::SocketGuard::new("lobby");
+    let result = follow_lobby(&mut socket, &state).await;
+    socket::close(socket, result).await;
+}
+
+async fn follow_lobby(socket: &mut WebSocket, state: &server::State) -> Result<(), SocketError> {
+    let mut lobby = state.subscribe_lobby();
+    lobby.borrow_and_update();
+    socket::send(socket, &game::ToBrowser::Lobby(state.lobby_games())).await?;
 
     loop {
         tokio::select! {
-            _ = sleep(Duration::from_secs(10)) => {
-                debug!("Socket: Ping");
-                socket.send(Message::Ping(vec![])).await.unwrap();
+            _ = sleep(Duration::from_secs(10)) => socket::ping(socket).await?,
+            Ok(_) = lobby.changed() => {
+                let games = server::lobby_list(&lobby.borrow());
+                socket::send(socket, &game::ToBrowser::Lobby(games)).await?;
+            }
+            text = socket::recv(socket) => {
+                if text?.is_none() {
+                    return Ok(());
+                }
             }
-            _ = conn.game_state.changed() => {
+        }
+    }
+}
+
+/// Send the joined game to the client, optionally seat a bot opposite them,
+/// then relay messages until the socket closes. With `patches`, changes to
+/// the game are sent as `Patch` messages rather than the whole state.
+async fn play(
+    mut socket: GameSocket,
+    mut conn: server::Connection,
+    bot: Option<bot::Strategy>,
+    patches: bool,
+) {
+    let _socket_guard = metrics::SocketGuard::new("game");
+    let result = relay(&mut socket, &mut conn, bot, patches).await;
+    socket.close(result).await;
+}
+
+async fn relay(
+    client: &mut impl Transport,
+    conn: &mut server::Connection,
+    bot: Option<bot::Strategy>,
+    patches: bool,
+) -> Result<(), SocketError> {
+    // the state the client has, which patches are made against
+    let mut sent = conn.game_state.borrow().clone();
+    let joined = game::ToBrowser::JoinedGame {
+        protocol_version: game::PROTOCOL_VERSION,
+        token: conn.game_id.clone(),
+        player_id: conn.player_id,
+        role: conn.role,
+        session: conn.session.clone(),
+        state: sent.clone(),
+    };
+    client.send(&joined).await?;
+
+    if let Some(strategy) = bot {
+        if let Err(e) = server::add_bot(&conn.game, strategy) {
+            client.send(&e.into()).await?;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            _ = sleep(Duration::from_secs(10)) => client.keep_alive().await?,
+            changed = conn.game_state.changed() => {
+                if changed.is_err() {
+                    return Err(SocketError::Gone("Game closed"));
+                }
                 let new_state = {
                     conn.game_state.borrow().clone()
                     // make sure to release the borrow immediately
                 };
+                if !new_state.is_present(conn.player_id) {
+                    return Err(SocketError::Refused(conn.removal_reason()));
+                }
                 // trace!("Socket: Sending game state change: {:?}", new_state);
-                let json = serde_json::to_string(&game::ToBrowser::GameState(new_state)).unwrap();
-                socket.send(Message::Text(json)).await.unwrap();
+                if !patches {
+                    client.send(&game::ToBrowser::GameState(new_state)).await?;
+                    continue;
+                }
+                let msg = match patch::diff(&sent, &new_state) {
+                    // nothing to send, so the client stays on the version it has
+                    Some(changes) if changes.is_empty() => continue,
+                    Some(changes) => game::ToBrowser::Patch {
+                        from: sent.version,
+                        version: new_state.version,
+                        changes,
+                    },
+                    None => game::ToBrowser::GameState(new_state.clone()),
+                };
+                client.send(&msg).await?;
+                sent = new_state;
             }
-            msg = socket.recv() => {
-                match msg {
-                    Some(raw_msg) => {
-                        debug!("Socket: Received message: {:?}", raw_msg);
-                        match raw_msg {
-                            Ok(Message::Text(json)) => {
-                                let parsed: game::FromBrowser = serde_json::from_str(&json).unwrap();
-                                debug!("Socket: Parsed message: {:?}", parsed);
-
-                                let server_err = {
-                                    let mut game = conn.game.lock().unwrap();
-                                    let result = game.handle_msg(conn.player.id, parsed);
-                                    match result {
-                                        Ok(changed) => {
-                                            if changed {
-                                                game.broadcast_state();
-                                            }
-                                            None
-                                        }
-
-                                        Err(e) => {
-                                            Some(e)
-                                        }
-                                    }
-                                    // release lock
-                                };
-
-                                if let Some(e) = server_err {
-                                    debug!("Socket: Error handling message: {:?}", e);
-                                    let json = serde_json::to_string(&game::ToBrowser::Error(e)).unwrap();
-                                    socket.send(Message::Text(json)).await.unwrap();
-                                }
-                            }
-
-                            Ok(Message::Close(_)) => {
-                                debug!("Socket: Client closed connection");
-                                return;
-                            }
-
-                            Ok(Message::Pong(_)) => {}
-
-                            Ok(Message::Ping(_)) => {}
-
-                            Ok(Message::Binary(_)) => {
-                                debug!("Socket: Received unexpected binary message, closing connection");
-                                return;
-                            }
-
-                            Err(e) => {
-                                // most errors seem to be resets or disconnects
-                                debug!("Socket: Error: {:?}, closing connection", e);
-                                return;
-                            }
-                        }
-                    }
-                    None => {
-                        debug!("Socket: Client disconnected");
-                        return;
+            data = client.recv() => {
+                let data = match data? {
+                    Some(data) => data,
+                    None => return Ok(()),
+                };
+                let parsed = match client.encoding().decode(&data) {
+                    Ok(parsed) => parsed,
+                    Err((code, message)) => {
+                        debug!("Socket: Invalid message: {}", message);
+                        client.send(&game::ToBrowser::error(code, message)).await?;
+                        continue;
                     }
+                };
+                debug!("Socket: Parsed message: {:?}", parsed);
+
+                if let game::FromBrowser::Resync = parsed {
+                    sent = conn.game_state.borrow().clone();
+                    client.send(&game::ToBrowser::GameState(sent.clone())).await?;
+                    continue;
+                }
+                if let game::FromBrowser::FetchChat { before } = parsed {
+                    let (messages, more) = conn.chat_page(before);
+                    client.send(&game::ToBrowser::ChatPage { messages, more }).await?;
+                    continue;
+                }
+                if let Err(e) = conn.handle_msg(parsed) {
+                    debug!("Socket: Error handling message: {:?}", e);
+                    client.send(&e.into()).await?;
                 }
             }
         }
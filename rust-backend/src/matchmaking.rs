@@ -0,0 +1,191 @@
+// Queue of players waiting to be paired with a stranger
+use crate::game;
+use crate::rating::Profile;
+use crate::server::{self, Connection};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::{oneshot, watch};
+use tracing::debug;
+
+#[derive(Default)]
+pub struct Queue {
+    last_ticket_id: u64,
+    waiting: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    ticket_id: u64,
+    name: Option<String>,
+    profile: Option<Profile>,
+    matched: oneshot::Sender<Connection>,
+    position: watch::Sender<usize>,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue::default()
+    }
+
+    fn cancel(&mut self, ticket_id: u64) {
+        self.waiting.retain(|w| w.ticket_id != ticket_id);
+        self.update_positions();
+    }
+
+    fn update_positions(&self) {
+        for (i, waiter) in self.waiting.iter().enumerate() {
+            waiter.position.send_if_modified(|position| {
+                let changed = *position != i + 1;
+                *position = i + 1;
+                changed
+            });
+        }
+    }
+}
+
+impl std::fmt::Debug for Queue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue")
+            .field("waiting", &self.waiting.len())
+            .finish()
+    }
+}
+
+pub enum Entry {
+    /// Paired straight away with someone who was already waiting.
+    Matched(Connection),
+    /// Nobody was waiting, so the ticket holds our place in the queue.
+    Waiting(Ticket),
+}
+
+/// A place in the queue, which is given up when dropped.
+pub struct Ticket {
+    id: u64,
+    state: Arc<server::State>,
+    /// 1-based position in the queue.
+    pub position: watch::Receiver<usize>,
+    /// Resolves with the connection to the new game once an opponent arrives.
+    pub matched: oneshot::Receiver<Connection>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        debug!("Matchmaking: ticket {} left the queue", self.id);
+        self.state.matchmaking.lock().unwrap().cancel(self.id);
+    }
+}
+
+/// Pair the player with whoever has been waiting the longest, in a new game,
+/// or join the back of the queue if nobody is waiting.
+pub fn enter(
+    state: &Arc<server::State>,
+    name: Option<String>,
+    profile: Option<Profile>,
+) -> Result<Entry, game::Error> {
+    let mut queue = state.matchmaking.lock().unwrap();
+
+    while let Some(opponent) = queue.waiting.pop_front() {
+        queue.update_positions();
+        if opponent.matched.is_closed() {
+            continue;
+        }
+
+        let opponent_conn = server::join_or_new_game(
+            state.clone(),
+            None,
+            opponent.name,
+            None,
+            opponent.profile,
+            game::Options::default(),
+        )?;
+        let token = opponent_conn.game_id.clone();
+        if opponent.matched.send(opponent_conn).is_err() {
+            // they left just now; their connection is dropped and the game with it
+            continue;
+        }
+
+        debug!("Matchmaking: paired ticket {} in game '{}'", opponent.ticket_id, token);
+        let conn = server::join_or_new_game(
+            state.clone(),
+            Some(token),
+            name,
+            None,
+            profile,
+            game::Options::default(),
+        )?;
+        return Ok(Entry::Matched(conn));
+    }
+
+    queue.last_ticket_id += 1;
+    let id = queue.last_ticket_id;
+    let (matched_tx, matched_rx) = oneshot::channel();
+    let (position_tx, position_rx) = watch::channel(queue.waiting.len() + 1);
+    queue.waiting.push_back(Waiter {
+        ticket_id: id,
+        name,
+        profile,
+        matched: matched_tx,
+        position: position_tx,
+    });
+    debug!("Matchmaking: ticket {} is waiting", id);
+
+    Ok(Entry::Waiting(Ticket {
+        id,
+        state: state.clone(),
+        position: position_rx,
+        matched: matched_rx,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    fn server_state() -> Arc<server::State> {
+        let store = Arc::new(MemoryStore::new());
+        Arc::new(server::State::new(
+            "http://localhost/".to_string(),
+            store.clone(),
+            store,
+            Default::default(),
+        ))
+    }
+
+    fn waiting(entry: Result<Entry, game::Error>) -> Ticket {
+        match entry.unwrap() {
+            Entry::Waiting(ticket) => ticket,
+            Entry::Matched(_) => panic!("matched with nobody waiting"),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_next_player_is_paired_with_whoever_is_waiting() {
+        let state = server_state();
+        let mut ticket = waiting(enter(&state, Some("First".to_string()), None));
+        assert_eq!(*ticket.position.borrow(), 1);
+
+        let second = match enter(&state, Some("Second".to_string()), None).unwrap() {
+            Entry::Matched(conn) => conn,
+            Entry::Waiting(_) => panic!("not paired with the waiting player"),
+        };
+        let first = (&mut ticket.matched).await.unwrap();
+        assert_eq!(first.game_id, second.game_id);
+        assert_eq!(first.role, game::Role::Player);
+        assert_eq!(second.role, game::Role::Player);
+
+        let game = first.game.lock().unwrap();
+        let names: Vec<&str> = game.state.players.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["First", "Second"]);
+        assert!(state.matchmaking.lock().unwrap().waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_tickets_leave_the_queue() {
+        let state = server_state();
+        drop(waiting(enter(&state, Some("Gone".to_string()), None)));
+
+        let ticket = waiting(enter(&state, Some("Next".to_string()), None));
+        assert_eq!(*ticket.position.borrow(), 1);
+        assert!(state.games.read().unwrap().is_empty());
+    }
+}
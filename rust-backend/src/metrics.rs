@@ -0,0 +1,157 @@
+// Prometheus metrics, served at /metrics
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::{Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Games in `server::State`, updated when scraped.
+    pub games: IntGauge,
+    /// Seated players with a connection, updated when scraped.
+    pub players: IntGauge,
+    /// Updated when scraped.
+    pub spectators: IntGauge,
+    /// Open WebSockets and event streams by route, see `SocketGuard`.
+    pub sockets: IntGaugeVec,
+    /// Messages from browsers by `FromBrowser` variant.
+    pub messages: IntCounterVec,
+    /// Messages that were refused, by `FromBrowser` variant.
+    pub message_errors: IntCounterVec,
+    pub moves: IntCounter,
+    /// From the first move to the end of each finished game.
+    pub game_duration: Histogram,
+    /// Time spent waiting for locks, by lock.
+    pub lock_wait: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let games = IntGauge::new("tictactoe_games", "Games in progress").unwrap();
+        let players = IntGauge::new("tictactoe_players", "Connected players").unwrap();
+        let spectators = IntGauge::new("tictactoe_spectators", "Spectators").unwrap();
+        let sockets = IntGaugeVec::new(
+            Opts::new(
+                "tictactoe_sockets",
+                "Open WebSocket connections and event streams",
+            ),
+            &["route"],
+        )
+        .unwrap();
+        let messages = IntCounterVec::new(
+            Opts::new("tictactoe_messages_total", "Messages received from browsers"),
+            &["type"],
+        )
+        .unwrap();
+        let message_errors = IntCounterVec::new(
+            Opts::new(
+                "tictactoe_message_errors_total",
+                "Messages from browsers that were refused",
+            ),
+            &["type"],
+        )
+        .unwrap();
+        let moves = IntCounter::new("tictactoe_moves_total", "Moves played").unwrap();
+        let game_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "tictactoe_game_duration_seconds",
+                "Time from the first move to the end of a game",
+            )
+            .buckets(vec![
+                5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+            ]),
+        )
+        .unwrap();
+        let lock_wait = HistogramVec::new(
+            HistogramOpts::new("tictactoe_lock_wait_seconds", "Time spent waiting for locks")
+                .buckets(exponential_buckets(0.000_001, 4.0, 12).unwrap()),
+            &["lock"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(games.clone())).unwrap();
+        registry.register(Box::new(players.clone())).unwrap();
+        registry.register(Box::new(spectators.clone())).unwrap();
+        registry.register(Box::new(sockets.clone())).unwrap();
+        registry.register(Box::new(messages.clone())).unwrap();
+        registry.register(Box::new(message_errors.clone())).unwrap();
+        registry.register(Box::new(moves.clone())).unwrap();
+        registry.register(Box::new(game_duration.clone())).unwrap();
+        registry.register(Box::new(lock_wait.clone())).unwrap();
+
+        Metrics {
+            registry,
+            games,
+            players,
+            spectators,
+            sockets,
+            messages,
+            message_errors,
+            moves,
+            game_duration,
+            lock_wait,
+        }
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Counts an open WebSocket for as long as it lives.
+pub struct SocketGuard(&'static str);
+
+impl SocketGuard {
+    pub fn new(route: &'static str) -> SocketGuard {
+        metrics().sockets.with_label_values(&[route]).inc();
+        SocketGuard(route)
+    }
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        metrics().sockets.with_label_values(&[self.0]).dec();
+    }
+}
+
+fn observe_wait(lock: &str, started: Instant) {
+    metrics()
+        .lock_wait
+        .with_label_values(&[lock])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+/// Lock a mutex, recording how long it took under `lock`.
+pub fn timed_lock<'a, T>(mutex: &'a Mutex<T>, lock: &str) -> MutexGuard<'a, T> {
+    let started = Instant::now();
+    let guard = mutex.lock().unwrap();
+    observe_wait(lock, started);
+    guard
+}
+
+pub fn timed_read<'a, T>(rwlock: &'a RwLock<T>, lock: &str) -> RwLockReadGuard<'a, T> {
+    let started = Instant::now();
+    let guard = rwlock.read().unwrap();
+    observe_wait(lock, started);
+    guard
+}
+
+pub fn timed_write<'a, T>(rwlock: &'a RwLock<T>, lock: &str) -> RwLockWriteGuard<'a, T> {
+    let started = Instant::now();
+    let guard = rwlock.write().unwrap();
+    observe_wait(lock, started);
+    guard
+}
@@ -0,0 +1,310 @@
+// Incremental updates to the game state, for clients that ask for them
+use crate::game::{
+    ChatMessage, EndReason, EndState, MoveRecord, Player, PlayerID, RematchOffer, Spectator, State,
+    UltimateState,
+};
+use serde::Serialize;
+
+/// One change to the game state. A `ToBrowser::Patch` carries them in the
+/// order they should be applied.
+#[derive(Debug, Clone, Serialize)]
+pub enum Change {
+    Cell {
+        space: usize,
+        mark: char,
+    },
+    Turn(char),
+    Winner(Option<EndState>),
+    EndReason(Option<EndReason>),
+    Ultimate(Option<UltimateState>),
+    /// Appended to `moves`.
+    MovePlayed(MoveRecord),
+    TurnStartedAt(Option<u64>),
+    TakebackRequest(Option<PlayerID>),
+    DrawOffer(Option<PlayerID>),
+    RematchOffer(Option<RematchOffer>),
+    Private(bool),
+    /// Appended to `players`.
+    PlayerJoined(Player),
+    /// Replaces the player with the same id.
+    PlayerUpdated(Player),
+    PlayerLeft(PlayerID),
+    /// Appended to `spectators`.
+    SpectatorJoined(Spectator),
+    /// Replaces the spectator with the same id.
+    SpectatorUpdated(Spectator),
+    SpectatorLeft(PlayerID),
+    Muted(Vec<PlayerID>),
+    /// Appended to `chat`.
+    ChatAppended(ChatMessage),
+}
+
+/// The changes that turn `old` into `new`, or None when they are too
+/// different to describe that way and the whole state should be sent, such
+/// as after a rematch or a takeback.
+pub fn diff(old: &State, new: &State) -> Option<Vec<Change>> {
+    // settings that are fixed when the game is created
+    if old.mode != new.mode
+        || old.board_size != new.board_size
+        || old.win_length != new.win_length
+        || old.spectator_chat != new.spectator_chat
+        || old.chat_filter != new.chat_filter
+        || old.time_control != new.time_control
+        || old.created_at != new.created_at
+        || old.tournament != new.tournament
+        || old.board.len() != new.board.len()
+    {
+        return None;
+    }
+    // taking back moves or starting again
+    if !new.moves.starts_with(&old.moves) {
+        return None;
+    }
+
+    let mut changes = Vec::new();
+    for (space, (&before, &after)) in old.board.iter().zip(&new.board).enumerate() {
+        if before != after {
+            changes.push(Change::Cell { space, mark: after });
+        }
+    }
+    if old.turn != new.turn {
+        changes.push(Change::Turn(new.turn));
+    }
+    if old.winner != new.winner {
+        changes.push(Change::Winner(new.winner.clone()));
+    }
+    if old.end_reason != new.end_reason {
+        changes.push(Change::EndReason(new.end_reason));
+    }
+    if old.ultimate != new.ultimate {
+        changes.push(Change::Ultimate(new.ultimate.clone()));
+    }
+    for m in &new.moves[old.moves.len()..] {
+        changes.push(Change::MovePlayed(m.clone()));
+    }
+    if old.turn_started_at != new.turn_started_at {
+        changes.push(Change::TurnStartedAt(new.turn_started_at));
+    }
+    if old.takeback_request != new.takeback_request {
+        changes.push(Change::TakebackRequest(new.takeback_request));
+    }
+    if old.draw_offer != new.draw_offer {
+        changes.push(Change::DrawOffer(new.draw_offer));
+    }
+    if old.rematch_offer != new.rematch_offer {
+        changes.push(Change::RematchOffer(new.rematch_offer.clone()));
+    }
+    if old.private != new.private {
+        changes.push(Change::Private(new.private));
+    }
+
+    let players = diff_list(&old.players, &new.players, |p| p.id)?;
+    changes.extend(players.left.into_iter().map(Change::PlayerLeft));
+    changes.extend(players.joined.into_iter().map(Change::PlayerJoined));
+    changes.extend(players.updated.into_iter().map(Change::PlayerUpdated));
+
+    let spectators = diff_list(&old.spectators, &new.spectators, |s| s.id)?;
+    changes.extend(spectators.left.into_iter().map(Change::SpectatorLeft));
+    changes.extend(spectators.joined.into_iter().map(Change::SpectatorJoined));
+    changes.extend(spectators.updated.into_iter().map(Change::SpectatorUpdated));
+    if old.muted != new.muted {
+        changes.push(Change::Muted(new.muted.clone()));
+    }
+
+    let last_chat_id = old.chat.last().map(|c| c.id);
+    for msg in &new.chat {
+        if last_chat_id.is_none_or(|id| msg.id > id) {
+            changes.push(Change::ChatAppended(msg.clone()));
+        }
+    }
+
+    Some(changes)
+}
+
+struct ListChanges<T> {
+    left: Vec<PlayerID>,
+    joined: Vec<T>,
+    updated: Vec<T>,
+}
+
+/// Who left, joined or changed between two lists of players or spectators,
+/// or None if they were reordered, which patches can't express.
+fn diff_list<T: Clone + PartialEq>(
+    old: &[T],
+    new: &[T],
+    id: impl Fn(&T) -> PlayerID,
+) -> Option<ListChanges<T>> {
+    let mut changes = ListChanges {
+        left: Vec::new(),
+        joined: Vec::new(),
+        updated: Vec::new(),
+    };
+    for before in old {
+        if !new.iter().any(|after| id(after) == id(before)) {
+            changes.left.push(id(before));
+        }
+    }
+
+    // applying the changes keeps everyone who stayed in order, followed by
+    // whoever joined
+    let mut order = Vec::with_capacity(new.len());
+    for before in old {
+        if let Some(after) = new.iter().find(|after| id(after) == id(before)) {
+            if after != before {
+                changes.updated.push(after.clone());
+            }
+            order.push(id(after));
+        }
+    }
+    for after in new {
+        if !old.iter().any(|before| id(before) == id(after)) {
+            changes.joined.push(after.clone());
+            order.push(id(after));
+        }
+    }
+    if !order.iter().copied().eq(new.iter().map(id)) {
+        return None;
+    }
+
+    Some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{FromBrowser, Game, Options, TimeControl, CHAT_IN_STATE};
+
+    /// What a client does with a patch.
+    fn apply(old: &State, changes: Vec<Change>) -> State {
+        let mut state = old.clone();
+        for change in changes {
+            match change {
+                Change::Cell { space, mark } => state.board[space] = mark,
+                Change::Turn(turn) => state.turn = turn,
+                Change::Winner(winner) => state.winner = winner,
+                Change::EndReason(reason) => state.end_reason = reason,
+                Change::Ultimate(ultimate) => state.ultimate = ultimate,
+                Change::MovePlayed(m) => state.moves.push(m),
+                Change::TurnStartedAt(at) => state.turn_started_at = at,
+                Change::TakebackRequest(id) => state.takeback_request = id,
+                Change::DrawOffer(id) => state.draw_offer = id,
+                Change::RematchOffer(offer) => state.rematch_offer = offer,
+                Change::Private(private) => state.private = private,
+                Change::PlayerJoined(player) => state.players.push(player),
+                Change::PlayerUpdated(player) => {
+                    let idx = state.players.iter().position(|p| p.id == player.id);
+                    state.players[idx.unwrap()] = player;
+                }
+                Change::PlayerLeft(id) => state.players.retain(|p| p.id != id),
+                Change::SpectatorJoined(spectator) => state.spectators.push(spectator),
+                Change::SpectatorUpdated(spectator) => {
+                    let idx = state.spectators.iter().position(|s| s.id == spectator.id);
+                    state.spectators[idx.unwrap()] = spectator;
+                }
+                Change::SpectatorLeft(id) => state.spectators.retain(|s| s.id != id),
+                Change::Muted(muted) => state.muted = muted,
+                Change::ChatAppended(msg) => {
+                    state.chat.push(msg);
+                    let trimmed = state.chat.len().saturating_sub(CHAT_IN_STATE);
+                    state.chat.drain(..trimmed);
+                }
+            }
+        }
+        state
+    }
+
+    /// Every earlier state patched up to every later one gives the later
+    /// one, apart from the version, which comes with the patch.
+    fn assert_round_trips(states: &[State]) {
+        for (i, old) in states.iter().enumerate() {
+            for new in &states[i + 1..] {
+                let changes = diff(old, new).expect("states should be patchable");
+                let mut patched = apply(old, changes);
+                patched.version = new.version;
+                assert_eq!(
+                    serde_json::to_value(&patched).unwrap(),
+                    serde_json::to_value(new).unwrap()
+                );
+            }
+        }
+    }
+
+    fn step(game: &mut Game, states: &mut Vec<State>) {
+        game.broadcast_state();
+        states.push(game.state.clone());
+    }
+
+    #[test]
+    fn patches_bring_clients_up_to_date() {
+        let options = Options {
+            time_control: Some(TimeControl::PerMove { move_ms: 60_000 }),
+            ..Options::default()
+        };
+        let (mut game, _rx) = Game::new("test".to_string(), options);
+        let mut states = vec![game.state.clone()];
+
+        let x = game.add_player("X".to_string()).unwrap();
+        step(&mut game, &mut states);
+        let o = game.add_player("O".to_string()).unwrap();
+        let watcher = game.add_spectator("Watcher".to_string());
+        step(&mut game, &mut states);
+        game.handle_msg(x.id, FromBrowser::Move { space: 4 })
+            .unwrap();
+        game.handle_msg(
+            o.id,
+            FromBrowser::ChangeName {
+                new_name: "Oh".to_string(),
+            },
+        )
+        .unwrap();
+        step(&mut game, &mut states);
+        game.handle_msg(o.id, FromBrowser::Move { space: 0 })
+            .unwrap();
+        game.handle_msg(
+            x.id,
+            FromBrowser::Mute {
+                player_id: watcher.id,
+            },
+        )
+        .unwrap();
+        game.handle_msg(x.id, FromBrowser::OfferDraw).unwrap();
+        step(&mut game, &mut states);
+        game.remove_player(watcher.id);
+        game.handle_msg(o.id, FromBrowser::DeclineDraw).unwrap();
+        game.handle_msg(x.id, FromBrowser::SetPrivate { private: true })
+            .unwrap();
+        step(&mut game, &mut states);
+        game.add_spectator("Late".to_string());
+        game.handle_msg(x.id, FromBrowser::Move { space: 1 })
+            .unwrap();
+        game.handle_msg(o.id, FromBrowser::Move { space: 2 })
+            .unwrap();
+        step(&mut game, &mut states);
+        game.handle_msg(x.id, FromBrowser::Move { space: 7 })
+            .unwrap();
+        step(&mut game, &mut states);
+        game.remove_player(o.id);
+        step(&mut game, &mut states);
+
+        assert!(game.state.winner.is_some());
+        assert_round_trips(&states);
+    }
+
+    #[test]
+    fn patches_keep_only_the_latest_chat() {
+        let (mut game, _rx) = Game::new("test".to_string(), Options::default());
+        let player = game.add_player("Chatty".to_string()).unwrap();
+        let mut states = vec![game.state.clone()];
+        for count in [3, CHAT_IN_STATE - 1, CHAT_IN_STATE + 10] {
+            for i in 0..count {
+                let text = format!("Message {}", i);
+                game.handle_msg(player.id, FromBrowser::ChatMsg { text })
+                    .unwrap();
+            }
+            step(&mut game, &mut states);
+        }
+
+        assert_eq!(game.state.chat.len(), CHAT_IN_STATE);
+        assert_round_trips(&states);
+    }
+}
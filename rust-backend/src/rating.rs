@@ -0,0 +1,150 @@
+// Persistent player profiles and ELO ratings
+use serde::{Deserialize, Serialize};
+
+/// Rating of a new profile.
+pub const INITIAL_RATING: i32 = 1200;
+
+/// Most a rating can change by in one game.
+const K_FACTOR: f64 = 32.0;
+
+/// A player's identity across games. Clients identify themselves with a
+/// secret device key, and everyone else knows them by the public `id`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Profile {
+    pub id: String,
+    /// The name the player last joined a game with.
+    pub name: String,
+    pub rating: i32,
+    pub games: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl Profile {
+    pub fn new(id: String, name: String, created_at: u64) -> Profile {
+        Profile {
+            id,
+            name,
+            rating: INITIAL_RATING,
+            games: 0,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            created_at,
+        }
+    }
+}
+
+/// A finished game between two players who both have profiles.
+#[derive(Debug, Clone)]
+pub struct GameResult {
+    pub game_id: String,
+    /// Profile id of the player who played X.
+    pub x: String,
+    /// Profile id of the player who played O.
+    pub o: String,
+    /// Winning team, or None for a draw.
+    pub winner: Option<char>,
+    pub moves: usize,
+    /// Milliseconds since the Unix epoch.
+    pub finished_at: u64,
+}
+
+/// A stored result, with both players' ratings after the game.
+#[derive(Debug, Clone)]
+pub struct RecordedResult {
+    pub result: GameResult,
+    pub x_rating: i32,
+    pub o_rating: i32,
+}
+
+/// Update both players' ratings and totals for the result of a game between
+/// them. `x` and `o` must be the profiles named in `result`.
+pub fn apply_result(x: &mut Profile, o: &mut Profile, result: &GameResult) {
+    let x_score = match result.winner {
+        Some('X') => 1.0,
+        Some(_) => 0.0,
+        None => 0.5,
+    };
+    let (x_rating, o_rating) = updated_ratings(x.rating, o.rating, x_score);
+    x.rating = x_rating;
+    o.rating = o_rating;
+
+    for (profile, score) in [(x, x_score), (o, 1.0 - x_score)] {
+        profile.games += 1;
+        if score == 1.0 {
+            profile.wins += 1;
+        } else if score == 0.0 {
+            profile.losses += 1;
+        } else {
+            profile.draws += 1;
+        }
+    }
+}
+
+/// New ratings of two players after a game in which the first scored
+/// `score` (1 for a win, 0.5 for a draw, 0 for a loss).
+fn updated_ratings(a: i32, b: i32, score: f64) -> (i32, i32) {
+    let expected = 1.0 / (1.0 + 10f64.powf(f64::from(b - a) / 400.0));
+    let change = (K_FACTOR * (score - expected)).round() as i32;
+    (a + change, b - change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(winner: Option<char>) -> GameResult {
+        GameResult {
+            game_id: "game".to_string(),
+            x: "x".to_string(),
+            o: "o".to_string(),
+            winner,
+            moves: 5,
+            finished_at: 0,
+        }
+    }
+
+    fn players(x_rating: i32, o_rating: i32) -> (Profile, Profile) {
+        let mut x = Profile::new("x".to_string(), "X".to_string(), 0);
+        let mut o = Profile::new("o".to_string(), "O".to_string(), 0);
+        x.rating = x_rating;
+        o.rating = o_rating;
+        (x, o)
+    }
+
+    #[test]
+    fn evenly_matched_players_trade_half_the_k_factor() {
+        let (mut x, mut o) = players(INITIAL_RATING, INITIAL_RATING);
+        apply_result(&mut x, &mut o, &result(Some('X')));
+        assert_eq!((x.rating, o.rating), (1216, 1184));
+        assert_eq!((x.games, x.wins, x.losses, x.draws), (1, 1, 0, 0));
+        assert_eq!((o.games, o.wins, o.losses, o.draws), (1, 0, 1, 0));
+    }
+
+    #[test]
+    fn upsets_are_worth_more() {
+        let (mut x, mut o) = players(1000, 1400);
+        apply_result(&mut x, &mut o, &result(Some('X')));
+        assert_eq!((x.rating, o.rating), (1029, 1371));
+
+        let (mut x, mut o) = players(1000, 1400);
+        apply_result(&mut x, &mut o, &result(Some('O')));
+        assert_eq!((x.rating, o.rating), (997, 1403));
+    }
+
+    #[test]
+    fn draws_move_ratings_towards_each_other() {
+        let (mut x, mut o) = players(INITIAL_RATING, INITIAL_RATING);
+        apply_result(&mut x, &mut o, &result(None));
+        assert_eq!((x.rating, o.rating), (INITIAL_RATING, INITIAL_RATING));
+        assert_eq!((x.draws, o.draws), (1, 1));
+
+        let (mut x, mut o) = players(1000, 1400);
+        apply_result(&mut x, &mut o, &result(None));
+        assert_eq!((x.rating, o.rating), (1013, 1387));
+    }
+}
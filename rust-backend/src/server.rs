@@ -1,108 +1,367 @@
 // Server state and stats
+use crate::bot;
+use crate::chat_filter;
 use crate::game;
-use rand::{distributions::Alphanumeric, Rng};
+use crate::matchmaking;
+use crate::metrics;
+use crate::rating;
+use crate::storage::{GameStore, ProfileStore};
+use crate::tournament;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::sync::{Arc, Mutex, RwLock};
-use tokio::sync::watch;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use tokio::sync::{mpsc, watch};
 use tokio::time::{sleep, Duration};
-use tracing::debug;
+use tracing::{debug, error, info};
 
 #[derive(Debug)]
 pub struct State {
     pub frontend_url: String,
     pub games: RwLock<HashMap<String, Arc<Mutex<game::Game>>>>,
+    pub store: Arc<dyn GameStore>,
+    pub profiles: Arc<dyn ProfileStore>,
+    pub matchmaking: Mutex<matchmaking::Queue>,
+    /// Tournaments, which are only kept in memory.
+    pub tournaments: RwLock<HashMap<String, Arc<Mutex<tournament::Tournament>>>>,
+    /// Checks chat in every game.
+    pub chat_filter: Arc<chat_filter::Filter>,
+    /// Where messages POSTed by clients playing over server-sent events go,
+    /// by session. See `events`.
+    pub event_streams: Mutex<HashMap<String, mpsc::Sender<String>>>,
+    /// Games with a free seat, by token. Kept up to date by `watch_game`.
+    lobby: watch::Sender<HashMap<String, game::LobbyGame>>,
 }
 
 impl State {
-    pub fn new(frontend_url: String) -> State {
+    pub fn new(
+        frontend_url: String,
+        store: Arc<dyn GameStore>,
+        profiles: Arc<dyn ProfileStore>,
+        chat_filter: chat_filter::Filter,
+    ) -> State {
         State {
             frontend_url,
             games: RwLock::new(HashMap::new()),
+            store,
+            profiles,
+            matchmaking: Mutex::new(matchmaking::Queue::new()),
+            tournaments: RwLock::new(HashMap::new()),
+            chat_filter: Arc::new(chat_filter),
+            event_streams: Mutex::new(HashMap::new()),
+            lobby: watch::channel(HashMap::new()).0,
         }
     }
 
+    /// Open games, newest first.
+    pub fn lobby_games(&self) -> Vec<game::LobbyGame> {
+        lobby_list(&self.lobby.borrow())
+    }
+
+    /// Notified whenever a game is added to, changed in or removed from the
+    /// lobby. Read it with `lobby_list`.
+    pub fn subscribe_lobby(&self) -> watch::Receiver<HashMap<String, game::LobbyGame>> {
+        self.lobby.subscribe()
+    }
+
+    fn update_lobby(&self, id: &str, entry: Option<game::LobbyGame>) {
+        self.lobby.send_if_modified(|lobby| match entry {
+            Some(entry) => lobby.insert(id.to_string(), entry.clone()) != Some(entry),
+            None => lobby.remove(id).is_some(),
+        });
+    }
+
+    /// Blocks on the store, so call from a blocking task.
     pub fn delete_game(&self, id: &str) {
-        let mut games = self.games.write().unwrap();
+        let mut games = metrics::timed_write(&self.games, "games_write");
         games.remove(id);
+        drop(games);
+        self.update_lobby(id, None);
+
+        if let Err(e) = self.store.delete(id) {
+            error!("Failed to delete game '{}' from store: {}", id, e);
+        }
+    }
+}
+
+pub fn lobby_list(lobby: &HashMap<String, game::LobbyGame>) -> Vec<game::LobbyGame> {
+    let mut games: Vec<game::LobbyGame> = lobby.values().cloned().collect();
+    games.sort_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then_with(|| a.token.cmp(&b.token))
+    });
+    games
+}
+
+/// Bring back every game saved in the store, for use at startup. Tournaments
+/// aren't saved, so games from them are deleted instead.
+pub fn restore_games(state: &Arc<State>) -> Result<usize, String> {
+    let snapshots = state.store.load_all()?;
+    let mut count = 0;
+
+    for snapshot in snapshots {
+        if snapshot.state.tournament.is_some() {
+            info!("Deleting tournament game '{}'", snapshot.id);
+            state.delete_game(&snapshot.id);
+            continue;
+        }
+        count += 1;
+
+        let (game, rx) = game::Game::from_snapshot(snapshot);
+        let held_seats = game.held_seats();
+        let bots: Vec<(game::PlayerID, bot::Strategy)> = game
+            .state
+            .players
+            .iter()
+            .filter_map(|p| p.bot.map(|strategy| (p.id, strategy)))
+            .collect();
+        info!(
+            "Restoring game '{}' with {} players",
+            game.id,
+            game.state.players.len()
+        );
+
+        let game = insert_game(state, game, rx);
+        for (player_id, connection_id) in held_seats {
+            hold_seat(&game, player_id, connection_id);
+        }
+        for (player_id, strategy) in bots {
+            spawn_bot(&game, player_id, strategy);
+        }
+    }
+
+    Ok(count)
+}
+
+/// Make a game available to join, and spawn the process that runs its
+/// clock, records its rated results, saves it after every change and deletes
+/// it once it has been empty for a minute.
+fn insert_game(
+    state: &Arc<State>,
+    mut game: game::Game,
+    rx: watch::Receiver<game::State>,
+) -> Arc<Mutex<game::Game>> {
+    let id = game.id.clone();
+    let (results_tx, results_rx) = mpsc::unbounded_channel();
+    game.report_results_to(results_tx);
+    game.filter_chat_with(state.chat_filter.clone());
+    let game = Arc::new(Mutex::new(game));
+    tokio::spawn(watch_game(
+        state.clone(),
+        id.clone(),
+        Arc::downgrade(&game),
+        rx,
+        results_rx,
+    ));
+    metrics::timed_write(&state.games, "games_write").insert(id, game.clone());
+    game
+}
+
+async fn watch_game(
+    state: Arc<State>,
+    id: String,
+    game: Weak<Mutex<game::Game>>,
+    mut rx: watch::Receiver<game::State>,
+    mut results: mpsc::UnboundedReceiver<rating::GameResult>,
+) {
+    loop {
+        let (empty, deadline) = {
+            let current = rx.borrow();
+            state.update_lobby(&id, current.lobby_game(&id));
+            (is_empty(&current), current.next_deadline())
+        };
+        if empty {
+            debug!(
+                "Game '{}' is empty, deleting in 1 minute if still empty",
+                &id
+            );
+        }
+
+        tokio::select! {
+            changed = rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = sleep(Duration::from_secs(60)), if empty => {
+                if is_empty(&rx.borrow()) {
+                    break;
+                }
+                continue;
+            }
+            _ = sleep_until_unix_millis(deadline.unwrap_or(0)), if deadline.is_some() => {
+                if let Some(game) = game.upgrade() {
+                    let mut game = lock_game(&game);
+                    let flagged = game.check_clock();
+                    if flagged {
+                        debug!("Game '{}': {} ran out of time", &id, game.state.turn);
+                    }
+                    if game.expire_rematch_offer() || flagged {
+                        game.broadcast_state();
+                    }
+                }
+                continue;
+            }
+            Some(result) = results.recv() => {
+                let store_state = state.clone();
+                let recorded =
+                    tokio::task::spawn_blocking(move || store_state.profiles.record_result(&result))
+                        .await;
+                match recorded {
+                    Ok(Ok((x, o))) => {
+                        if let Some(game) = game.upgrade() {
+                            let mut game = lock_game(&game);
+                            game.update_ratings(&[x, o]);
+                            game.broadcast_state();
+                        }
+                    }
+                    Ok(Err(e)) => error!("Failed to record result of game '{}': {}", &id, e),
+                    Err(e) => error!("Failed to record result of game '{}': {}", &id, e),
+                }
+                continue;
+            }
+        }
+
+        let snapshot = match game.upgrade() {
+            Some(game) => {
+                let game = lock_game(&game);
+                // the tournament running it would be gone after a restart
+                if game.state.tournament.is_some() {
+                    continue;
+                }
+                game.snapshot()
+            }
+            None => break,
+        };
+        let store_state = state.clone();
+        let saved = tokio::task::spawn_blocking(move || store_state.store.save(&snapshot)).await;
+        if let Ok(Err(e)) = saved {
+            error!("Failed to save game '{}': {}", &id, e);
+        }
     }
+
+    let delete_id = id.clone();
+    let _ = tokio::task::spawn_blocking(move || state.delete_game(&delete_id)).await;
+    debug!("Deleted game '{}'", &id);
 }
 
 pub fn join_or_new_game(
     state: Arc<State>,
     token: Option<String>,
     player_name: Option<String>,
-) -> Result<Connection, String> {
+    session: Option<String>,
+    profile: Option<rating::Profile>,
+    options: game::Options,
+) -> Result<Connection, game::Error> {
     let mut is_new_game = false;
     let game: Arc<Mutex<game::Game>> = token
         .clone()
         .and_then(|token| {
             // if we have a token, try to get the game matching the token
-            let games = state.games.read().unwrap();
-            games.get(&token).map(|g| g.clone())
+            let games = metrics::timed_read(&state.games, "games_read");
+            games.get(&token).cloned()
         })
         .unwrap_or_else(|| {
             // if after that we still don't have a game, create a new one
             is_new_game = true;
 
-            let id: String = token.unwrap_or_else(|| random_token());
+            let id: String = token.unwrap_or_else(|| game::random_token(7));
             // TODO: when generating random token, check for collisions
 
-            let (game, mut rx) = game::Game::new(id.clone());
+            let (game, rx) = game::Game::new(id.clone(), options);
+            insert_game(&state, game, rx)
+        });
 
-            let game = Arc::new(Mutex::new(game));
+    let mut unlocked_game = lock_game(&game);
 
-            // spawn cleanup process
-            {
-                let state = state.clone();
-                let id = id.clone();
-                tokio::spawn(async move {
-                    loop {
-                        while rx.changed().await.is_ok() {
-                            if rx.borrow().players.is_empty() {
-                                break;
-                            }
-                        }
+    let joined = unlocked_game.join(
+        player_name.unwrap_or_else(|| "Unnamed Player".to_string()),
+        session.as_deref(),
+        profile.as_ref(),
+    )?;
+    unlocked_game.broadcast_state();
 
-                        debug!("Game '{}' is empty, deleting in 1 minute if still empty", &id);
-                        sleep(Duration::from_secs(60)).await;
-                        if rx.borrow().players.is_empty() {
-                            break;
-                        }
-                    }
-                    state.delete_game(&id);
-                    debug!("Deleted game '{}'", &id);
-                });
-            }
+    Ok(Connection {
+        game_id: unlocked_game.id.clone(),
+        player_id: joined.player_id,
+        role: joined.role,
+        session: joined.session,
+        connection_id: joined.connection_id,
+        game: game.clone(),
+        is_new_game,
+        game_state: unlocked_game.state_changes.subscribe(),
+    })
+}
 
-            state.games.write().unwrap().insert(id, game.clone());
-            game
-        });
+/// Create a private tournament game with seats reserved for two players, who
+/// take them with the sessions returned alongside their ids.
+pub fn new_reserved_game(
+    state: &Arc<State>,
+    options: game::Options,
+    tournament_id: &str,
+    names: [String; 2],
+) -> Result<(Arc<Mutex<game::Game>>, Vec<game::ReservedSeat>), String> {
+    let id = loop {
+        let id = game::random_token(7);
+        if !metrics::timed_read(&state.games, "games_read").contains_key(&id) {
+            break id;
+        }
+    };
+    let (mut game, rx) = game::Game::new(
+        id,
+        game::Options {
+            private: true,
+            ..options
+        },
+    );
+    game.state.tournament = Some(tournament_id.to_string());
+    let seats = names
+        .into_iter()
+        .map(|name| game.reserve_seat(name))
+        .collect::<Result<Vec<_>, game::Error>>()
+        .map_err(|e| e.to_string())?;
+    game.broadcast_state();
+
+    Ok((insert_game(state, game, rx), seats))
+}
 
-    let mut unlocked_game = game.lock().unwrap();
+async fn sleep_until_unix_millis(deadline: u64) {
+    let now = game::unix_millis();
+    sleep(Duration::from_millis(deadline.saturating_sub(now))).await;
+}
 
-    match unlocked_game.add_player(player_name.unwrap_or_else(|| "Unnamed Player".to_string())) {
-        Ok(_player) => {
-            unlocked_game.broadcast_state();
+/// No one is playing or watching.
+fn is_empty(state: &game::State) -> bool {
+    state.players.is_empty() && state.spectators.is_empty()
+}
 
-            Ok(Connection {
-                game_id: unlocked_game.id.clone(),
-                player: _player,
-                game: game.clone(),
-                is_new_game: is_new_game,
-                game_state: unlocked_game.state_changes.subscribe(),
-            })
-        }
-        Err(e) => Err(e),
-    }
+/// Lock a game, recording the wait in the metrics.
+pub fn lock_game(game: &Mutex<game::Game>) -> MutexGuard<'_, game::Game> {
+    metrics::timed_lock(game, "game")
 }
 
-fn random_token() -> String {
-    return rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(7)
-        .map(char::from)
+/// Refresh the gauges that are counted from the games, before a scrape.
+pub fn update_gauges(state: &State) {
+    let games: Vec<Arc<Mutex<game::Game>>> = metrics::timed_read(&state.games, "games_read")
+        .values()
+        .cloned()
         .collect();
+    let (mut players, mut spectators) = (0, 0);
+    for game in &games {
+        let game = lock_game(game);
+        players += game
+            .state
+            .players
+            .iter()
+            .filter(|p| p.connected && p.bot.is_none())
+            .count();
+        spectators += game.state.spectators.len();
+    }
+
+    let metrics = metrics::metrics();
+    metrics.games.set(games.len() as i64);
+    metrics.players.set(players as i64);
+    metrics.spectators.set(spectators as i64);
 }
 
 impl Display for State {
@@ -110,27 +369,197 @@ impl Display for State {
         write!(
             f,
             "AppState(GameCount: {})",
-            self.games.read().unwrap().len()
+            metrics::timed_read(&self.games, "games_read").len()
         )
     }
 }
 
+/// How long a disconnected player's seat is held for them.
+const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 pub struct Connection {
     pub game_id: String,
     pub game: Arc<Mutex<game::Game>>,
-    pub player: game::Player,
+    pub player_id: game::PlayerID,
+    pub role: game::Role,
+    pub session: String,
+    pub connection_id: game::ConnectionID,
     pub is_new_game: bool,
     pub game_state: watch::Receiver<game::State>,
 }
 
+impl Connection {
+    /// Apply a message from this connection's player to the game, and let
+    /// everyone know if anything changed.
+    pub fn handle_msg(&self, msg: game::FromBrowser) -> Result<(), game::Error> {
+        let name = msg.name();
+        metrics::metrics().messages.with_label_values(&[name]).inc();
+        let result = self.apply_msg(msg);
+        if result.is_err() {
+            metrics::metrics()
+                .message_errors
+                .with_label_values(&[name])
+                .inc();
+        }
+        result
+    }
+
+    /// Why the player is no longer in the game, once the host has removed
+    /// them.
+    pub fn removal_reason(&self) -> game::Error {
+        if lock_game(&self.game).was_banned(self.player_id) {
+            game::Error::Banned
+        } else {
+            game::Error::Kicked
+        }
+    }
+
+    /// See `game::Game::chat_page`.
+    pub fn chat_page(&self, before: usize) -> (Vec<game::ChatMessage>, bool) {
+        lock_game(&self.game).chat_page(before)
+    }
+
+    fn apply_msg(&self, msg: game::FromBrowser) -> Result<(), game::Error> {
+        if let game::FromBrowser::AddBot { strategy } = msg {
+            if self.role == game::Role::Spectator {
+                return Err(game::Error::SpectatorsCanOnlyWatch);
+            }
+            add_bot(&self.game, strategy)?;
+            return Ok(());
+        }
+
+        let mut game = lock_game(&self.game);
+        if game.handle_msg(self.player_id, msg)? {
+            game.broadcast_state();
+        }
+        Ok(())
+    }
+}
+
+/// Seat a computer player in the game, and spawn the task that plays for it.
+pub fn add_bot(
+    game: &Arc<Mutex<game::Game>>,
+    strategy: bot::Strategy,
+) -> Result<game::Player, game::Error> {
+    let player = {
+        let mut unlocked_game = lock_game(game);
+        let player = unlocked_game.add_bot(strategy)?;
+        unlocked_game.broadcast_state();
+        player
+    };
+
+    spawn_bot(game, player.id, strategy);
+    Ok(player)
+}
+
+fn spawn_bot(game: &Arc<Mutex<game::Game>>, bot_id: game::PlayerID, strategy: bot::Strategy) {
+    let rx = lock_game(game).state_changes.subscribe();
+    tokio::spawn(run_bot(game.clone(), bot_id, strategy, rx));
+}
+
+/// How long a bot pretends to think before moving.
+const BOT_MOVE_DELAY: Duration = Duration::from_millis(500);
+
+async fn run_bot(
+    game: Arc<Mutex<game::Game>>,
+    bot_id: game::PlayerID,
+    strategy: bot::Strategy,
+    mut rx: watch::Receiver<game::State>,
+) {
+    loop {
+        let state = rx.borrow_and_update().clone();
+
+        let team = match state.players.iter().find(|p| p.id == bot_id) {
+            Some(p) => p.team,
+            None => {
+                debug!("Bot {}: no longer in game, stopping", bot_id);
+                return;
+            }
+        };
+
+        // leave once all the humans have, so the game can be cleaned up
+        if state.players.iter().all(|p| p.bot.is_some()) {
+            debug!("Bot {}: no humans left, leaving game", bot_id);
+            let mut game = lock_game(&game);
+            game.remove_player(bot_id);
+            game.broadcast_state();
+            return;
+        }
+
+        // good sports, bots always allow takebacks, and rematches once the
+        // game is over, so that a rematch can't be used to get out of a loss
+        let answer = if matches!(state.takeback_request, Some(id) if id != bot_id) {
+            Some(game::FromBrowser::AcceptTakeback)
+        } else if matches!(&state.rematch_offer, Some(offer) if offer.player_id != bot_id) {
+            if state.winner.is_some() {
+                Some(game::FromBrowser::AcceptRematch)
+            } else {
+                Some(game::FromBrowser::DeclineRematch)
+            }
+        } else {
+            None
+        };
+        if let Some(answer) = answer {
+            let mut game = lock_game(&game);
+            if game.handle_msg(bot_id, answer).is_ok() {
+                game.broadcast_state();
+            }
+        } else if state.winner.is_none() && state.players.len() == 2 && state.turn == team {
+            sleep(BOT_MOVE_DELAY).await;
+
+            let board = state.board.clone();
+            let space = tokio::task::spawn_blocking(move || bot::choose_move(&state, strategy))
+                .await
+                .ok()
+                .flatten();
+
+            if let Some(space) = space {
+                let mut game = lock_game(&game);
+                // the board may have been reset while we were thinking
+                if game.state.board == board {
+                    match game.take_turn(bot_id, space) {
+                        Ok(()) => game.broadcast_state(),
+                        Err(e) => debug!("Bot {}: move {} rejected: {}", bot_id, space, e),
+                    }
+                }
+            }
+        }
+
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
 impl Drop for Connection {
     fn drop(&mut self) {
         debug!(
-            "Connection: Player {:?} disconnected, removing from game",
-            self.player
+            "Connection: {:?} {} disconnected",
+            self.role, self.player_id
         );
-        let mut game = self.game.lock().unwrap();
-        game.remove_player(self.player.id);
+        let mut game = lock_game(&self.game);
+        let hold_seat = game.disconnect(self.player_id, self.connection_id);
         game.broadcast_state();
+
+        drop(game);
+
+        if hold_seat {
+            self::hold_seat(&self.game, self.player_id, self.connection_id);
+        }
     }
 }
+
+/// Give the player a grace period to reconnect before their seat is given up.
+fn hold_seat(
+    game: &Arc<Mutex<game::Game>>,
+    player_id: game::PlayerID,
+    connection_id: game::ConnectionID,
+) {
+    let game = game.clone();
+    tokio::spawn(async move {
+        sleep(RECONNECT_GRACE_PERIOD).await;
+        let mut game = lock_game(&game);
+        game.expire_seat(player_id, connection_id);
+        game.broadcast_state();
+    });
+}
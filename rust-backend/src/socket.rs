@@ -0,0 +1,193 @@
+// Sending and receiving over WebSockets, and why the server closes them
+use crate::encoding::{Encoded, Encoding};
+use crate::game::{self, ToBrowser};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use tracing::debug;
+
+/// Why the server stopped serving a socket.
+#[derive(Debug)]
+pub enum SocketError {
+    /// Sending failed, so the client is already gone.
+    Send(axum::Error),
+    /// Receiving failed, usually because the connection was reset.
+    Recv(axum::Error),
+    /// The client sent a binary frame when using JSON, or a text frame when
+    /// using a binary encoding.
+    UnsupportedData,
+    /// The client couldn't be joined to a game or the matchmaking queue, or
+    /// the host removed it from its game.
+    Refused(game::Error),
+    /// The game, queue or tournament the socket was following went away.
+    Gone(&'static str),
+    /// A message to the client couldn't be serialized.
+    Encode(String),
+    /// The client stopped following an event stream, see `events`.
+    Disconnected,
+}
+
+impl std::fmt::Display for SocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocketError::Send(e) => write!(f, "Sending failed: {}", e),
+            SocketError::Recv(e) => write!(f, "Receiving failed: {}", e),
+            SocketError::UnsupportedData => write!(f, "Received the wrong type of frame"),
+            SocketError::Refused(message) => write!(f, "Refused: {}", message),
+            SocketError::Gone(reason) => write!(f, "{}", reason),
+            SocketError::Encode(e) => write!(f, "Couldn't serialize message: {}", e),
+            SocketError::Disconnected => write!(f, "Event stream closed"),
+        }
+    }
+}
+
+impl SocketError {
+    /// The close frame to send, unless the connection is already broken.
+    fn close_frame(&self) -> Option<CloseFrame<'static>> {
+        let (code, reason) = match self {
+            SocketError::Send(_) | SocketError::Recv(_) | SocketError::Disconnected => return None,
+            SocketError::UnsupportedData => (
+                close_code::UNSUPPORTED,
+                "Frame type doesn't match the encoding",
+            ),
+            SocketError::Refused(game::Error::Internal) => (close_code::ERROR, "Internal error"),
+            SocketError::Refused(_) => (close_code::POLICY, "Refused"),
+            SocketError::Gone(reason) => (close_code::AWAY, *reason),
+            SocketError::Encode(_) => (close_code::ERROR, "Internal error"),
+        };
+        Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })
+    }
+}
+
+/// A connection to a browser that a game can be played over, so that
+/// `relay` works the same whether it's a WebSocket or an event stream.
+pub trait Transport {
+    async fn send(&mut self, msg: &ToBrowser) -> Result<(), SocketError>;
+    /// The next message from the client, still encoded, or None once it has
+    /// gone.
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>, SocketError>;
+    /// Called when nothing has been sent for a while, so that idle
+    /// connections aren't dropped along the way.
+    async fn keep_alive(&mut self) -> Result<(), SocketError>;
+    fn encoding(&self) -> Encoding;
+}
+
+/// A WebSocket that a game is played over, in the encoding the client asked
+/// for.
+pub struct GameSocket {
+    socket: WebSocket,
+    encoding: Encoding,
+}
+
+impl GameSocket {
+    pub fn new(socket: WebSocket, encoding: Encoding) -> GameSocket {
+        GameSocket { socket, encoding }
+    }
+
+    /// Finish with a socket once its loop has ended. Errors the client can
+    /// do something about are sent to it first, then the socket is closed
+    /// with a code saying why.
+    pub async fn close(mut self, result: Result<(), SocketError>) {
+        let e = match result {
+            Ok(()) => {
+                debug!("Socket: Client closed connection");
+                return;
+            }
+            Err(e) => e,
+        };
+        debug!("Socket: Closing connection: {}", e);
+
+        let msg = match &e {
+            SocketError::Refused(e) => Some(ToBrowser::from(*e)),
+            SocketError::UnsupportedData => Some(ToBrowser::from(game::Error::UnsupportedData)),
+            _ => None,
+        };
+        if let Some(msg) = msg {
+            if self.send(&msg).await.is_err() {
+                return;
+            }
+        }
+        if let Some(frame) = e.close_frame() {
+            // the client may have gone already, and there's nothing more to do
+            let _ = self.socket.send(Message::Close(Some(frame))).await;
+        }
+    }
+}
+
+impl Transport for GameSocket {
+    async fn send(&mut self, msg: &ToBrowser) -> Result<(), SocketError> {
+        send_encoded(&mut self.socket, self.encoding, msg).await
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>, SocketError> {
+        recv_encoded(&mut self.socket, self.encoding).await
+    }
+
+    async fn keep_alive(&mut self) -> Result<(), SocketError> {
+        ping(&mut self.socket).await
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+/// Send a message as JSON, for the sockets that only speak JSON.
+pub async fn send(socket: &mut WebSocket, msg: &ToBrowser) -> Result<(), SocketError> {
+    send_encoded(socket, Encoding::Json, msg).await
+}
+
+async fn send_encoded(
+    socket: &mut WebSocket,
+    encoding: Encoding,
+    msg: &ToBrowser,
+) -> Result<(), SocketError> {
+    let frame = match encoding.encode(msg).map_err(SocketError::Encode)? {
+        Encoded::Text(text) => Message::Text(text),
+        Encoded::Binary(data) => Message::Binary(data),
+    };
+    socket.send(frame).await.map_err(SocketError::Send)
+}
+
+pub async fn ping(socket: &mut WebSocket) -> Result<(), SocketError> {
+    debug!("Socket: Ping");
+    socket
+        .send(Message::Ping(vec![]))
+        .await
+        .map_err(SocketError::Send)
+}
+
+/// The next text frame from a socket that only speaks JSON, see
+/// `recv_encoded`.
+pub async fn recv(socket: &mut WebSocket) -> Result<Option<Vec<u8>>, SocketError> {
+    recv_encoded(socket, Encoding::Json).await
+}
+
+/// The data of the next frame from the client, skipping pings and pongs, or
+/// None once the client has closed the socket. Text frames are expected for
+/// JSON, and binary frames for the other encodings.
+async fn recv_encoded(
+    socket: &mut WebSocket,
+    encoding: Encoding,
+) -> Result<Option<Vec<u8>>, SocketError> {
+    loop {
+        match socket.recv().await {
+            Some(Ok(Message::Text(text))) if !encoding.is_binary() => {
+                return Ok(Some(text.into_bytes()))
+            }
+            Some(Ok(Message::Binary(data))) if encoding.is_binary() => return Ok(Some(data)),
+            Some(Ok(Message::Text(_))) | Some(Ok(Message::Binary(_))) => {
+                return Err(SocketError::UnsupportedData)
+            }
+            Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+            Some(Ok(Message::Close(_))) | None => return Ok(None),
+            Some(Err(e)) => return Err(SocketError::Recv(e)),
+        }
+    }
+}
+
+/// Finish with a socket that only speaks JSON, see `GameSocket::close`.
+pub async fn close(socket: WebSocket, result: Result<(), SocketError>) {
+    GameSocket::new(socket, Encoding::Json).close(result).await
+}
@@ -0,0 +1,343 @@
+// Persistent storage for games, so they survive a restart, and for player
+// profiles and results
+use crate::game::{random_token, unix_millis, Snapshot};
+use crate::rating::{self, GameResult, Profile, RecordedResult};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Somewhere to keep game snapshots. Calls may block, so call them from a
+/// blocking task when inside the async runtime.
+pub trait GameStore: Send + Sync + std::fmt::Debug {
+    /// Insert or replace the snapshot of a game.
+    fn save(&self, snapshot: &Snapshot) -> Result<(), String>;
+    fn delete(&self, id: &str) -> Result<(), String>;
+    fn load_all(&self) -> Result<Vec<Snapshot>, String>;
+}
+
+/// Player profiles and the results of rated games. Calls may block, like
+/// `GameStore`.
+pub trait ProfileStore: Send + Sync + std::fmt::Debug {
+    /// The profile belonging to a device key, created if the key is new.
+    /// Its name is updated to `name`.
+    fn device_profile(&self, device_key: &str, name: &str) -> Result<Profile, String>;
+    fn profile(&self, id: &str) -> Result<Option<Profile>, String>;
+    /// Store the result and update both players' ratings, returning the X and
+    /// O players' profiles afterwards.
+    fn record_result(&self, result: &GameResult) -> Result<(Profile, Profile), String>;
+    /// Results of games finished at or after `since` (milliseconds since the
+    /// Unix epoch), oldest first.
+    fn results_since(&self, since: u64) -> Result<Vec<RecordedResult>, String>;
+}
+
+/// Keeps snapshots for as long as the process lives. The default, when no
+/// database is configured.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    games: Mutex<HashMap<String, Snapshot>>,
+    profiles: Mutex<MemoryProfiles>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryProfiles {
+    by_id: HashMap<String, Profile>,
+    /// Device key to profile id.
+    devices: HashMap<String, String>,
+    results: Vec<RecordedResult>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl GameStore for MemoryStore {
+    fn save(&self, snapshot: &Snapshot) -> Result<(), String> {
+        let mut games = self.games.lock().unwrap();
+        games.insert(snapshot.id.clone(), snapshot.clone());
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), String> {
+        self.games.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn load_all(&self) -> Result<Vec<Snapshot>, String> {
+        Ok(self.games.lock().unwrap().values().cloned().collect())
+    }
+}
+
+impl ProfileStore for MemoryStore {
+    fn device_profile(&self, device_key: &str, name: &str) -> Result<Profile, String> {
+        let mut profiles = self.profiles.lock().unwrap();
+        let id = match profiles.devices.get(device_key) {
+            Some(id) => id.clone(),
+            None => {
+                let profile = Profile::new(random_token(12), name.to_string(), unix_millis());
+                let id = profile.id.clone();
+                profiles.devices.insert(device_key.to_string(), id.clone());
+                profiles.by_id.insert(id.clone(), profile);
+                id
+            }
+        };
+        let profile = profiles.by_id.get_mut(&id).ok_or("Profile not found")?;
+        profile.name = name.to_string();
+        Ok(profile.clone())
+    }
+
+    fn profile(&self, id: &str) -> Result<Option<Profile>, String> {
+        Ok(self.profiles.lock().unwrap().by_id.get(id).cloned())
+    }
+
+    fn record_result(&self, result: &GameResult) -> Result<(Profile, Profile), String> {
+        let mut profiles = self.profiles.lock().unwrap();
+        let mut x = profiles.by_id.get(&result.x).cloned().ok_or("Profile not found")?;
+        let mut o = profiles.by_id.get(&result.o).cloned().ok_or("Profile not found")?;
+        rating::apply_result(&mut x, &mut o, result);
+        profiles.by_id.insert(x.id.clone(), x.clone());
+        profiles.by_id.insert(o.id.clone(), o.clone());
+        profiles.results.push(RecordedResult {
+            result: result.clone(),
+            x_rating: x.rating,
+            o_rating: o.rating,
+        });
+        Ok((x, o))
+    }
+
+    fn results_since(&self, since: u64) -> Result<Vec<RecordedResult>, String> {
+        let profiles = self.profiles.lock().unwrap();
+        Ok(profiles
+            .results
+            .iter()
+            .filter(|r| r.result.finished_at >= since)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Stores each game as a JSON snapshot in a SQLite database file.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<SqliteStore, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS games (
+                id TEXT PRIMARY KEY,
+                snapshot TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS profiles (
+                id TEXT PRIMARY KEY,
+                device_key TEXT NOT NULL UNIQUE,
+                name TEXT NOT NULL,
+                rating INTEGER NOT NULL,
+                games INTEGER NOT NULL,
+                wins INTEGER NOT NULL,
+                losses INTEGER NOT NULL,
+                draws INTEGER NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS results (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                game_id TEXT NOT NULL,
+                x_profile TEXT NOT NULL REFERENCES profiles (id),
+                o_profile TEXT NOT NULL REFERENCES profiles (id),
+                winner TEXT,
+                moves INTEGER NOT NULL,
+                x_rating INTEGER NOT NULL,
+                o_rating INTEGER NOT NULL,
+                finished_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS results_finished_at ON results (finished_at);",
+        )
+        .map_err(|e| e.to_string())?;
+
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl GameStore for SqliteStore {
+    fn save(&self, snapshot: &Snapshot) -> Result<(), String> {
+        let json = serde_json::to_string(snapshot).map_err(|e| e.to_string())?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO games (id, snapshot, updated_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT (id) DO UPDATE SET snapshot = excluded.snapshot, updated_at = excluded.updated_at",
+            params![snapshot.id, json, unix_millis() as i64],
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM games WHERE id = ?1", params![id])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn load_all(&self) -> Result<Vec<Snapshot>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT id, snapshot FROM games")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(|e| e.to_string())?;
+
+        let mut snapshots = Vec::new();
+        for row in rows {
+            let (id, json) = row.map_err(|e| e.to_string())?;
+            // skip rather than fail on snapshots from an incompatible version
+            match serde_json::from_str(&json) {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(e) => tracing::warn!("Skipping unreadable snapshot of game '{}': {}", id, e),
+            }
+        }
+        Ok(snapshots)
+    }
+}
+
+const PROFILE_COLUMNS: &str = "id, name, rating, games, wins, losses, draws, created_at";
+
+fn profile_from_row(row: &rusqlite::Row) -> rusqlite::Result<Profile> {
+    Ok(Profile {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        rating: row.get(2)?,
+        games: row.get(3)?,
+        wins: row.get(4)?,
+        losses: row.get(5)?,
+        draws: row.get(6)?,
+        created_at: row.get::<_, i64>(7)? as u64,
+    })
+}
+
+fn load_profile(conn: &Connection, id: &str) -> Result<Option<Profile>, String> {
+    conn.query_row(
+        &format!("SELECT {} FROM profiles WHERE id = ?1", PROFILE_COLUMNS),
+        params![id],
+        profile_from_row,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+fn save_profile(conn: &Connection, profile: &Profile) -> Result<(), String> {
+    conn.execute(
+        "UPDATE profiles SET name = ?2, rating = ?3, games = ?4, wins = ?5, losses = ?6, draws = ?7
+        WHERE id = ?1",
+        params![
+            profile.id,
+            profile.name,
+            profile.rating,
+            profile.games,
+            profile.wins,
+            profile.losses,
+            profile.draws
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+impl ProfileStore for SqliteStore {
+    fn device_profile(&self, device_key: &str, name: &str) -> Result<Profile, String> {
+        let conn = self.conn.lock().unwrap();
+        let profile = Profile::new(random_token(12), name.to_string(), unix_millis());
+        conn.execute(
+            "INSERT INTO profiles (id, device_key, name, rating, games, wins, losses, draws, created_at)
+            VALUES (?1, ?2, ?3, ?4, 0, 0, 0, 0, ?5)
+            ON CONFLICT (device_key) DO UPDATE SET name = excluded.name",
+            params![
+                profile.id,
+                device_key,
+                profile.name,
+                profile.rating,
+                profile.created_at as i64
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM profiles WHERE device_key = ?1",
+                PROFILE_COLUMNS
+            ),
+            params![device_key],
+            profile_from_row,
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    fn profile(&self, id: &str) -> Result<Option<Profile>, String> {
+        load_profile(&self.conn.lock().unwrap(), id)
+    }
+
+    fn record_result(&self, result: &GameResult) -> Result<(Profile, Profile), String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        let mut x = load_profile(&tx, &result.x)?.ok_or("Profile not found")?;
+        let mut o = load_profile(&tx, &result.o)?.ok_or("Profile not found")?;
+        rating::apply_result(&mut x, &mut o, result);
+        save_profile(&tx, &x)?;
+        save_profile(&tx, &o)?;
+        tx.execute(
+            "INSERT INTO results (game_id, x_profile, o_profile, winner, moves, x_rating, o_rating, finished_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                result.game_id,
+                result.x,
+                result.o,
+                result.winner.map(String::from),
+                result.moves as i64,
+                x.rating,
+                o.rating,
+                result.finished_at as i64
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok((x, o))
+    }
+
+    fn results_since(&self, since: u64) -> Result<Vec<RecordedResult>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT game_id, x_profile, o_profile, winner, moves, x_rating, o_rating, finished_at
+                FROM results WHERE finished_at >= ?1 ORDER BY finished_at, id",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![since as i64], |row| {
+                Ok(RecordedResult {
+                    result: GameResult {
+                        game_id: row.get(0)?,
+                        x: row.get(1)?,
+                        o: row.get(2)?,
+                        winner: row
+                            .get::<_, Option<String>>(3)?
+                            .and_then(|w| w.chars().next()),
+                        moves: row.get::<_, i64>(4)? as usize,
+                        finished_at: row.get::<_, i64>(7)? as u64,
+                    },
+                    x_rating: row.get(5)?,
+                    o_rating: row.get(6)?,
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+}
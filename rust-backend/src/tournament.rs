@@ -0,0 +1,853 @@
+// Tournaments: rounds of games between registered entrants
+use crate::chat_filter::{self, Verdict};
+use crate::game::{self, EndState, PlayerID};
+use crate::server;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{debug, error, info};
+
+/// Index of an entrant in `State.entrants`.
+pub type EntrantID = usize;
+
+pub const MAX_ENTRANTS: usize = 64;
+
+/// How long entrants have to turn up to a match before forfeiting it.
+const NO_SHOW_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How many times a drawn elimination match is replayed before the higher
+/// seed goes through.
+const MAX_REPLAYS: usize = 2;
+
+/// How long a finished tournament stays around to be looked at.
+const FINISHED_TOURNAMENT_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Format {
+    /// Everyone plays everyone once.
+    RoundRobin,
+    /// A fixed number of rounds, each pairing entrants with similar scores
+    /// who haven't played each other yet.
+    Swiss { rounds: usize },
+    /// Losers are knocked out, in a bracket seeded in order of registration.
+    SingleElimination,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Status {
+    Registering,
+    Running,
+    Finished,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entrant {
+    pub id: EntrantID,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Outcome {
+    Win(EntrantID),
+    Draw,
+    /// No opponent this round, which counts as a win.
+    Bye,
+    /// Won by the entrant who showed up, if either did.
+    Forfeit(Option<EntrantID>),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Match {
+    /// In elimination brackets the higher seed is first. The second is None
+    /// for a bye.
+    pub players: (EntrantID, Option<EntrantID>),
+    /// Token of the game the match is played in.
+    pub game_id: Option<String>,
+    /// None until the match is over.
+    pub outcome: Option<Outcome>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Standing {
+    pub entrant_id: EntrantID,
+    pub name: String,
+    /// 1 for a win or bye, 0.5 for a draw.
+    pub points: f64,
+    pub played: u32,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+/// Everything about a tournament that anyone may see.
+#[derive(Debug, Clone, Serialize)]
+pub struct State {
+    pub id: String,
+    pub name: String,
+    pub format: Format,
+    pub status: Status,
+    pub entrants: Vec<Entrant>,
+    /// Matches of each round so far.
+    pub rounds: Vec<Vec<Match>>,
+    /// Best first.
+    pub standings: Vec<Standing>,
+    pub champion: Option<EntrantID>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+/// Where an entrant plays their current match: connect to `/ws` with the
+/// game `token` and `session` to take the reserved seat.
+#[derive(Debug, Clone, Serialize)]
+pub struct Seat {
+    pub token: String,
+    pub session: String,
+}
+
+#[derive(Debug)]
+pub struct Tournament {
+    pub id: String,
+    pub state: State,
+    pub state_changes: watch::Sender<State>,
+    /// Options for every game of the tournament.
+    pub options: game::Options,
+    /// Secret needed to start the tournament.
+    host_key: String,
+    /// Secret of each entrant, by id, for finding their seats.
+    entrant_keys: Vec<String>,
+    /// Seats in the current round's matches.
+    seats: HashMap<EntrantID, Seat>,
+    /// Entrant in each slot of the current elimination round, None for an
+    /// empty slot.
+    bracket: Vec<Option<EntrantID>>,
+    chat_filter: Arc<chat_filter::Filter>,
+}
+
+impl Tournament {
+    pub fn new(
+        id: String,
+        name: String,
+        format: Format,
+        options: game::Options,
+    ) -> Result<Tournament, String> {
+        if let Format::Swiss { rounds } = format {
+            if !(1..=20).contains(&rounds) {
+                return Err("Swiss tournaments must have between 1 and 20 rounds".to_string());
+            }
+        }
+        options.validate()?;
+
+        let state = State {
+            id: id.clone(),
+            name,
+            format,
+            status: Status::Registering,
+            entrants: Vec::new(),
+            rounds: Vec::new(),
+            standings: Vec::new(),
+            champion: None,
+            created_at: game::unix_millis(),
+        };
+        Ok(Tournament {
+            id,
+            state_changes: watch::channel(state.clone()).0,
+            state,
+            options,
+            host_key: game::random_token(32),
+            entrant_keys: Vec::new(),
+            seats: HashMap::new(),
+            bracket: Vec::new(),
+            chat_filter: Arc::default(),
+        })
+    }
+
+    /// Check entrants' names with `filter`, at the strictness of the
+    /// tournament's games.
+    pub fn filter_names_with(&mut self, filter: Arc<chat_filter::Filter>) {
+        self.chat_filter = filter;
+    }
+
+    pub fn host_key(&self) -> &str {
+        &self.host_key
+    }
+
+    /// Add an entrant, returning their id and the secret key they use to
+    /// find their seats.
+    pub fn register(&mut self, name: String) -> Result<(EntrantID, String), String> {
+        if self.state.status != Status::Registering {
+            return Err("Registration is closed".to_string());
+        }
+        if self.state.entrants.len() >= MAX_ENTRANTS {
+            return Err("Tournament is full".to_string());
+        }
+
+        let name = match self
+            .chat_filter
+            .check(game::clip_name(&name), self.options.chat_filter)
+        {
+            Verdict::Allow(name) => name,
+            Verdict::Reject(e) => return Err(e.to_string()),
+            Verdict::Drop => return Err("That name isn't allowed".to_string()),
+        };
+
+        let id = self.state.entrants.len();
+        self.state.entrants.push(Entrant { id, name });
+        let key = game::random_token(32);
+        self.entrant_keys.push(key.clone());
+        self.update_standings();
+        Ok((id, key))
+    }
+
+    pub fn entrant_by_key(&self, key: &str) -> Option<EntrantID> {
+        self.entrant_keys.iter().position(|k| k == key)
+    }
+
+    /// Close registration. Rounds are then played by `run`.
+    pub fn start(&mut self, host_key: &str) -> Result<(), String> {
+        if host_key != self.host_key {
+            return Err("Only the host can start the tournament".to_string());
+        }
+        if self.state.status != Status::Registering {
+            return Err("Tournament has already started".to_string());
+        }
+        let count = self.state.entrants.len();
+        if count < 2 {
+            return Err("Not enough entrants".to_string());
+        }
+
+        self.state.status = Status::Running;
+        if self.state.format == Format::SingleElimination {
+            self.bracket = seed_order(count.next_power_of_two())
+                .into_iter()
+                .map(|seed| if seed < count { Some(seed) } else { None })
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// The seat of an entrant whose current match hasn't finished.
+    pub fn seat_for(&self, entrant: EntrantID) -> Option<Seat> {
+        let playing = self.state.rounds.last().is_some_and(|round| {
+            round.iter().any(|m| {
+                m.outcome.is_none()
+                    && (m.players.0 == entrant || m.players.1 == Some(entrant))
+            })
+        });
+        if playing {
+            self.seats.get(&entrant).cloned()
+        } else {
+            None
+        }
+    }
+
+    /// Pair the entrants for the next round, returning its index, or finish
+    /// the tournament and return None if there are no rounds left.
+    fn next_round(&mut self) -> Option<usize> {
+        let count = self.state.entrants.len();
+        let played = self.state.rounds.len();
+        let pairs = match self.state.format {
+            Format::RoundRobin if played < round_robin_rounds(count) => {
+                Some(round_robin_pairs(count, played))
+            }
+            Format::RoundRobin => None,
+            Format::Swiss { rounds } if played < rounds => Some(self.swiss_pairs()),
+            Format::Swiss { .. } => None,
+            Format::SingleElimination => {
+                if played > 0 {
+                    self.advance_bracket();
+                }
+                if self.bracket.iter().flatten().count() > 1 {
+                    Some(bracket_pairs(&self.bracket))
+                } else {
+                    None
+                }
+            }
+        };
+
+        let pairs = match pairs {
+            Some(pairs) => pairs,
+            None => {
+                self.finish();
+                return None;
+            }
+        };
+        self.seats.clear();
+        self.state.rounds.push(
+            pairs
+                .into_iter()
+                .map(|players| Match {
+                    players,
+                    game_id: None,
+                    outcome: None,
+                })
+                .collect(),
+        );
+        Some(self.state.rounds.len() - 1)
+    }
+
+    fn finish(&mut self) {
+        self.state.status = Status::Finished;
+        self.state.champion = match self.state.format {
+            Format::SingleElimination => self.bracket.iter().flatten().next().copied(),
+            _ => self.state.standings.first().map(|s| s.entrant_id),
+        };
+        self.seats.clear();
+    }
+
+    /// Pair entrants on the same score where possible, highest first,
+    /// avoiding rematches. With an odd number of entrants the lowest ranked
+    /// who hasn't had a bye yet gets one.
+    fn swiss_pairs(&self) -> Vec<(EntrantID, Option<EntrantID>)> {
+        let mut met: HashSet<(EntrantID, EntrantID)> = HashSet::new();
+        let mut had_bye: HashSet<EntrantID> = HashSet::new();
+        for m in self.state.rounds.iter().flatten() {
+            match m.players {
+                (a, Some(b)) => {
+                    met.insert((a, b));
+                    met.insert((b, a));
+                }
+                (a, None) => {
+                    had_bye.insert(a);
+                }
+            }
+        }
+
+        let mut unpaired: Vec<EntrantID> =
+            self.state.standings.iter().map(|s| s.entrant_id).collect();
+        let bye = if unpaired.len() % 2 == 1 {
+            let idx = unpaired
+                .iter()
+                .rposition(|e| !had_bye.contains(e))
+                .unwrap_or(unpaired.len() - 1);
+            Some(unpaired.remove(idx))
+        } else {
+            None
+        };
+
+        let mut pairs = Vec::new();
+        while !unpaired.is_empty() {
+            let a = unpaired.remove(0);
+            let b = unpaired
+                .iter()
+                .position(|b| !met.contains(&(a, *b)))
+                .unwrap_or(0);
+            pairs.push((a, Some(unpaired.remove(b))));
+        }
+        pairs.extend(bye.map(|entrant| (entrant, None)));
+        pairs
+    }
+
+    /// Move the winners of the current elimination round into the next.
+    fn advance_bracket(&mut self) {
+        let mut matches = self.state.rounds.last().into_iter().flatten();
+        self.bracket = self
+            .bracket
+            .chunks(2)
+            .map(|pair| match pair {
+                [None, None] => None,
+                _ => matches.next().and_then(|m| match m.outcome {
+                    Some(Outcome::Win(winner)) | Some(Outcome::Forfeit(Some(winner))) => {
+                        Some(winner)
+                    }
+                    // the higher seed goes through drawn matches
+                    Some(Outcome::Draw) | Some(Outcome::Bye) => Some(m.players.0),
+                    Some(Outcome::Forfeit(None)) | None => None,
+                }),
+            })
+            .collect();
+    }
+
+    fn set_game(&mut self, round: usize, idx: usize, game_id: String, seats: [(EntrantID, String); 2]) {
+        for (entrant, session) in seats {
+            self.seats.insert(
+                entrant,
+                Seat {
+                    token: game_id.clone(),
+                    session,
+                },
+            );
+        }
+        self.state.rounds[round][idx].game_id = Some(game_id);
+    }
+
+    fn record(&mut self, round: usize, idx: usize, outcome: Outcome) {
+        self.state.rounds[round][idx].outcome = Some(outcome);
+        self.update_standings();
+    }
+
+    fn update_standings(&mut self) {
+        let mut standings: Vec<Standing> = self
+            .state
+            .entrants
+            .iter()
+            .map(|e| Standing {
+                entrant_id: e.id,
+                name: e.name.clone(),
+                points: 0.0,
+                played: 0,
+                wins: 0,
+                draws: 0,
+                losses: 0,
+            })
+            .collect();
+
+        for m in self.state.rounds.iter().flatten() {
+            let outcome = match m.outcome {
+                Some(outcome) => outcome,
+                None => continue,
+            };
+            for entrant in [Some(m.players.0), m.players.1].into_iter().flatten() {
+                let standing = &mut standings[entrant];
+                standing.played += 1;
+                match outcome {
+                    Outcome::Win(winner) | Outcome::Forfeit(Some(winner)) if winner == entrant => {
+                        standing.wins += 1;
+                        standing.points += 1.0;
+                    }
+                    Outcome::Bye => {
+                        standing.wins += 1;
+                        standing.points += 1.0;
+                    }
+                    Outcome::Draw => {
+                        standing.draws += 1;
+                        standing.points += 0.5;
+                    }
+                    _ => standing.losses += 1,
+                }
+            }
+        }
+
+        standings.sort_by(|a, b| {
+            b.points
+                .total_cmp(&a.points)
+                .then_with(|| b.wins.cmp(&a.wins))
+                .then_with(|| a.entrant_id.cmp(&b.entrant_id))
+        });
+        self.state.standings = standings;
+    }
+
+    pub fn broadcast_state(&self) {
+        self.state_changes.send_replace(self.state.clone());
+    }
+}
+
+fn round_robin_rounds(count: usize) -> usize {
+    if count.is_multiple_of(2) {
+        count - 1
+    } else {
+        count
+    }
+}
+
+/// Pairings for one round of the circle method: the first entrant stays put
+/// while the rest rotate, and an odd entrant out gets a bye.
+fn round_robin_pairs(count: usize, round: usize) -> Vec<(EntrantID, Option<EntrantID>)> {
+    let mut circle: Vec<Option<EntrantID>> = (0..count).map(Some).collect();
+    if count % 2 == 1 {
+        circle.push(None);
+    }
+    let n = circle.len();
+    circle[1..].rotate_right(round % (n - 1));
+
+    (0..n / 2)
+        .filter_map(|i| match (circle[i], circle[n - 1 - i]) {
+            (Some(a), b) => Some((a, b)),
+            (None, Some(b)) => Some((b, None)),
+            (None, None) => None,
+        })
+        .collect()
+}
+
+/// Seeds (from 0) in bracket order, so that the top seeds can only meet in
+/// the last rounds: 0, 7, 3, 4, 1, 6, 2, 5 for a bracket of 8.
+fn seed_order(size: usize) -> Vec<usize> {
+    let mut order = vec![0];
+    while order.len() < size {
+        let len = order.len();
+        order = order
+            .iter()
+            .flat_map(|&seed| [seed, 2 * len - 1 - seed])
+            .collect();
+    }
+    order
+}
+
+fn bracket_pairs(bracket: &[Option<EntrantID>]) -> Vec<(EntrantID, Option<EntrantID>)> {
+    bracket
+        .chunks(2)
+        .filter_map(|pair| match pair {
+            [Some(a), Some(b)] => Some((*a.min(b), Some(*a.max(b)))),
+            [Some(a), None] | [None, Some(a)] => Some((*a, None)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Create a tournament that entrants can register for.
+pub fn create(
+    state: &Arc<server::State>,
+    name: String,
+    format: Format,
+    options: game::Options,
+) -> Result<Arc<Mutex<Tournament>>, String> {
+    let id = loop {
+        let id = game::random_token(7);
+        if !state.tournaments.read().unwrap().contains_key(&id) {
+            break id;
+        }
+    };
+    let mut tournament = Tournament::new(id.clone(), name, format, options)?;
+    tournament.filter_names_with(state.chat_filter.clone());
+    let tournament = Arc::new(Mutex::new(tournament));
+    state
+        .tournaments
+        .write()
+        .unwrap()
+        .insert(id, tournament.clone());
+    Ok(tournament)
+}
+
+/// Close registration and start playing rounds.
+pub fn start(
+    state: &Arc<server::State>,
+    tournament: &Arc<Mutex<Tournament>>,
+    host_key: &str,
+) -> Result<(), String> {
+    {
+        let mut unlocked = tournament.lock().unwrap();
+        unlocked.start(host_key)?;
+        unlocked.broadcast_state();
+    }
+    tokio::spawn(run(state.clone(), tournament.clone()));
+    Ok(())
+}
+
+/// Play the rounds one after another until the tournament is over, then
+/// remove it once it has been finished for a while.
+async fn run(state: Arc<server::State>, tournament: Arc<Mutex<Tournament>>) {
+    let id = tournament.lock().unwrap().id.clone();
+    info!("Tournament '{}' started", &id);
+
+    loop {
+        let (round, matches, options, format) = {
+            let mut unlocked = tournament.lock().unwrap();
+            let round = unlocked.next_round();
+            unlocked.broadcast_state();
+            match round {
+                Some(round) => (
+                    round,
+                    unlocked.state.rounds[round].clone(),
+                    unlocked.options,
+                    unlocked.state.format,
+                ),
+                None => break,
+            }
+        };
+        debug!("Tournament '{}': round {} started", &id, round + 1);
+
+        // elimination matches need a winner
+        let replay_draws = format == Format::SingleElimination;
+        let mut playing = JoinSet::new();
+        for (idx, m) in matches.into_iter().enumerate() {
+            let (a, b) = match m.players {
+                (a, Some(b)) => (a, b),
+                (_, None) => {
+                    let mut unlocked = tournament.lock().unwrap();
+                    unlocked.record(round, idx, Outcome::Bye);
+                    unlocked.broadcast_state();
+                    continue;
+                }
+            };
+
+            let names = {
+                let unlocked = tournament.lock().unwrap();
+                [
+                    unlocked.state.entrants[a].name.clone(),
+                    unlocked.state.entrants[b].name.clone(),
+                ]
+            };
+            let (game, seats) = match server::new_reserved_game(&state, options, &id, names) {
+                Ok(created) => created,
+                Err(e) => {
+                    error!("Tournament '{}': failed to create a game: {}", &id, e);
+                    let mut unlocked = tournament.lock().unwrap();
+                    unlocked.record(round, idx, Outcome::Forfeit(None));
+                    unlocked.broadcast_state();
+                    continue;
+                }
+            };
+
+            let game_id = server::lock_game(&game).id.clone();
+            {
+                let mut unlocked = tournament.lock().unwrap();
+                unlocked.set_game(
+                    round,
+                    idx,
+                    game_id,
+                    [(a, seats[0].1.clone()), (b, seats[1].1.clone())],
+                );
+                unlocked.broadcast_state();
+            }
+            let players = [(a, seats[0].0), (b, seats[1].0)];
+            playing.spawn(async move {
+                let outcome = play_match(game.clone(), players, replay_draws).await;
+                let mut unlocked = server::lock_game(&game);
+                unlocked.release_absent_players();
+                unlocked.broadcast_state();
+                (idx, outcome)
+            });
+        }
+
+        while let Some(finished) = playing.join_next().await {
+            match finished {
+                Ok((idx, outcome)) => {
+                    let mut unlocked = tournament.lock().unwrap();
+                    unlocked.record(round, idx, outcome);
+                    unlocked.broadcast_state();
+                }
+                Err(e) => error!("Tournament '{}': match failed: {}", &id, e),
+            }
+        }
+    }
+
+    info!("Tournament '{}' finished", &id);
+    sleep(FINISHED_TOURNAMENT_TTL).await;
+    state.tournaments.write().unwrap().remove(&id);
+}
+
+/// Wait for a match to be decided. Entrants who haven't turned up within
+/// `NO_SHOW_TIMEOUT` forfeit.
+async fn play_match(
+    game: Arc<Mutex<game::Game>>,
+    players: [(EntrantID, PlayerID); 2],
+    replay_draws: bool,
+) -> Outcome {
+    let mut rx = server::lock_game(&game).state_changes.subscribe();
+    let no_show_deadline = Instant::now() + NO_SHOW_TIMEOUT;
+    let mut everyone_arrived = false;
+    let mut replays = 0;
+
+    loop {
+        let result = {
+            let state = rx.borrow_and_update();
+            match &state.winner {
+                Some(EndState::Win(team)) => Some(
+                    state
+                        .players
+                        .iter()
+                        .find(|p| p.team == *team)
+                        .and_then(|p| players.iter().find(|(_, id)| *id == p.id))
+                        .map_or(Outcome::Forfeit(None), |(entrant, _)| {
+                            Outcome::Win(*entrant)
+                        }),
+                ),
+                Some(EndState::Draw) => Some(Outcome::Draw),
+                None => None,
+            }
+        };
+        if let Some(outcome) = result {
+            if !everyone_arrived {
+                // decided by a forfeit, or played out before the deadline
+                let unlocked = server::lock_game(&game);
+                match players.map(|(_, id)| unlocked.has_connected(id)) {
+                    [true, true] => everyone_arrived = true,
+                    [true, false] => return Outcome::Forfeit(Some(players[0].0)),
+                    [false, true] => return Outcome::Forfeit(Some(players[1].0)),
+                    [false, false] => return Outcome::Forfeit(None),
+                }
+            }
+            if outcome == Outcome::Draw && replay_draws && replays < MAX_REPLAYS {
+                replays += 1;
+                let mut unlocked = server::lock_game(&game);
+                unlocked.rematch();
+                unlocked.broadcast_state();
+                continue;
+            }
+            return outcome;
+        }
+
+        tokio::select! {
+            changed = rx.changed() => {
+                if changed.is_err() {
+                    return Outcome::Forfeit(None);
+                }
+            }
+            _ = sleep_until(no_show_deadline), if !everyone_arrived => {
+                let mut unlocked = server::lock_game(&game);
+                let arrived = players.map(|(_, id)| unlocked.has_connected(id));
+                match arrived {
+                    [true, true] => everyone_arrived = true,
+                    [true, false] => unlocked.forfeit(Some(players[0].1)),
+                    [false, true] => unlocked.forfeit(Some(players[1].1)),
+                    [false, false] => unlocked.forfeit(None),
+                }
+                unlocked.broadcast_state();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registering(format: Format) -> Tournament {
+        Tournament::new(
+            "test".to_string(),
+            "Test".to_string(),
+            format,
+            game::Options::default(),
+        )
+        .unwrap()
+    }
+
+    fn started(format: Format, count: usize) -> Tournament {
+        let mut tournament = registering(format);
+        for i in 0..count {
+            tournament.register(format!("Entrant {}", i)).unwrap();
+        }
+        let host_key = tournament.host_key().to_string();
+        tournament.start(&host_key).unwrap();
+        tournament
+    }
+
+    /// Pair the next round and decide every match in it with `decide`,
+    /// returning the pairs, or None once the tournament is over.
+    fn play_round(
+        tournament: &mut Tournament,
+        decide: impl Fn(EntrantID, EntrantID) -> Outcome,
+    ) -> Option<Vec<(EntrantID, Option<EntrantID>)>> {
+        let round = tournament.next_round()?;
+        let pairs: Vec<_> = tournament.state.rounds[round]
+            .iter()
+            .map(|m| m.players)
+            .collect();
+        for (idx, &(a, b)) in pairs.iter().enumerate() {
+            let outcome = match b {
+                Some(b) => decide(a, b),
+                None => Outcome::Bye,
+            };
+            tournament.record(round, idx, outcome);
+        }
+        Some(pairs)
+    }
+
+    #[test]
+    fn entrant_names_are_filtered() {
+        let words = chat_filter::WordList::parse("badword\n!spam");
+        let filter = Arc::new(chat_filter::Filter::standard(words));
+        let mut tournament = registering(Format::RoundRobin);
+        tournament.filter_names_with(filter);
+        let (id, _) = tournament.register("  badword  ".to_string()).unwrap();
+        assert_eq!(tournament.state.entrants[id].name, "*******");
+        assert!(tournament.register("spam".to_string()).is_err());
+
+        tournament.options.chat_filter = chat_filter::Strictness::Strict;
+        assert_eq!(
+            tournament.register("badword".to_string()),
+            Err(game::Error::Profanity.to_string())
+        );
+        assert_eq!(tournament.state.entrants.len(), 1);
+    }
+
+    #[test]
+    fn round_robin_pairs_everyone_once() {
+        for count in 2..=9 {
+            let mut met = HashSet::new();
+            let mut byes = vec![0; count];
+            for round in 0..round_robin_rounds(count) {
+                let pairs = round_robin_pairs(count, round);
+                let mut playing = HashSet::new();
+                for (a, b) in pairs {
+                    assert!(playing.insert(a));
+                    match b {
+                        Some(b) => {
+                            assert!(playing.insert(b));
+                            assert!(met.insert((a.min(b), a.max(b))));
+                        }
+                        None => byes[a] += 1,
+                    }
+                }
+                assert_eq!(playing.len(), count);
+            }
+            assert_eq!(met.len(), count * (count - 1) / 2);
+            let expected_byes = if count % 2 == 1 { 1 } else { 0 };
+            assert!(byes.iter().all(|&b| b == expected_byes));
+        }
+    }
+
+    #[test]
+    fn seeds_only_meet_the_top_seeds_late() {
+        assert_eq!(seed_order(1), vec![0]);
+        assert_eq!(seed_order(2), vec![0, 1]);
+        assert_eq!(seed_order(8), vec![0, 7, 3, 4, 1, 6, 2, 5]);
+        for size in [4, 16, 64] {
+            let order = seed_order(size);
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, (0..size).collect::<Vec<_>>());
+            // the best plays the worst in the first round
+            assert!(order.chunks(2).all(|pair| pair[0] + pair[1] == size - 1));
+        }
+    }
+
+    #[test]
+    fn swiss_rounds_avoid_rematches() {
+        let mut tournament = started(Format::Swiss { rounds: 3 }, 4);
+        let mut met = HashSet::new();
+        while let Some(pairs) = play_round(&mut tournament, |a, _| Outcome::Win(a)) {
+            for (a, b) in pairs {
+                let b = b.unwrap();
+                assert!(met.insert((a.min(b), a.max(b))), "{} met {} again", a, b);
+            }
+        }
+        assert_eq!(met.len(), 6);
+        assert_eq!(tournament.state.status, Status::Finished);
+    }
+
+    #[test]
+    fn swiss_byes_go_to_different_entrants() {
+        let mut tournament = started(Format::Swiss { rounds: 5 }, 5);
+        let mut had_bye = HashSet::new();
+        while let Some(pairs) = play_round(&mut tournament, |_, b| Outcome::Win(b)) {
+            let byes: Vec<_> = pairs.iter().filter(|(_, b)| b.is_none()).collect();
+            assert_eq!(byes.len(), 1);
+            assert!(had_bye.insert(byes[0].0));
+        }
+        assert_eq!(had_bye.len(), 5);
+    }
+
+    #[test]
+    fn top_seeds_get_the_byes_in_an_uneven_bracket() {
+        let mut tournament = started(Format::SingleElimination, 5);
+        let first = play_round(&mut tournament, |a, _| Outcome::Win(a)).unwrap();
+        assert_eq!(first, vec![(0, None), (3, Some(4)), (1, None), (2, None)]);
+        let second = play_round(&mut tournament, |_, b| Outcome::Win(b)).unwrap();
+        assert_eq!(second, vec![(0, Some(3)), (1, Some(2))]);
+        let last = play_round(&mut tournament, |_, _| Outcome::Draw).unwrap();
+        assert_eq!(last, vec![(2, Some(3))]);
+        assert!(play_round(&mut tournament, |a, _| Outcome::Win(a)).is_none());
+        // the higher seed goes through a draw
+        assert_eq!(tournament.state.champion, Some(2));
+    }
+
+    #[test]
+    fn a_double_forfeit_leaves_the_slot_empty() {
+        let mut tournament = started(Format::SingleElimination, 4);
+        let first = play_round(&mut tournament, |a, b| {
+            if a == 0 {
+                Outcome::Forfeit(None)
+            } else {
+                Outcome::Win(b)
+            }
+        })
+        .unwrap();
+        assert_eq!(first, vec![(0, Some(3)), (1, Some(2))]);
+        // nobody is left to play the final against
+        assert!(play_round(&mut tournament, |a, _| Outcome::Win(a)).is_none());
+        assert_eq!(tournament.state.champion, Some(2));
+    }
+}
@@ -1,5 +1,5 @@
 use async_tungstenite::{tokio::connect_async, tungstenite::Message};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use futures::prelude::*;
 use serde::{Deserialize, Serialize};
 use tokio::{
@@ -7,15 +7,60 @@ use tokio::{
     task::JoinSet,
     time::{sleep, Duration, Instant},
 };
-use tracing::{debug, error, info, instrument, trace, warn};
-use tracing_subscriber;
+use tracing::{debug, error};
 
 #[derive(Debug, Parser)]
 struct Args {
     address: String,
     n: usize,
+    /// How messages are encoded on the wire
+    #[arg(long, value_enum, default_value_t = Encoding::Json)]
+    encoding: Encoding,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Encoding {
+    Json,
+    #[value(name = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Value of the server's `encoding` parameter.
+    fn param(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    fn encode(self, msg: &FromBrowser) -> Message {
+        match self {
+            Encoding::Json => Message::Text(serde_json::to_string(msg).unwrap()),
+            Encoding::MessagePack => Message::Binary(rmp_serde::to_vec_named(msg).unwrap()),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(msg, &mut buffer).unwrap();
+                Message::Binary(buffer)
+            }
+        }
+    }
+
+    fn decode(self, data: &[u8]) -> Result<ToBrowser, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::from_reader(data).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Version of the server's protocol these types match. Update both
+/// together when the server's `PROTOCOL_VERSION` changes.
+const PROTOCOL_VERSION: u32 = 3;
+
 static X_MOVES: [usize; 4] = [4, 1, 2, 8];
 static O_MOVES: [usize; 4] = [0, 7, 6, 3];
 
@@ -33,13 +78,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut p1_join_times: Vec<Duration> = Vec::with_capacity(args.n);
     let mut p2_join_times: Vec<Duration> = Vec::with_capacity(args.n);
     let mut turn_latency_samples: Vec<Duration> = Vec::with_capacity(args.n * 8);
+    let mut bytes_sent: usize = 0;
+    let mut bytes_received: usize = 0;
+    let mut messages_received: usize = 0;
+    let mut codec_time = Duration::ZERO;
 
     let start_time = Instant::now();
 
     // play n games
     let mut set = JoinSet::new();
     for i in 0..args.n {
-        set.spawn(play_test_game(i, args.address.clone()));
+        set.spawn(play_test_game(i, args.address.clone(), args.encoding));
     }
 
     while let Some(r) = set.join_next().await {
@@ -56,6 +105,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 p1_join_times.push(result.p1_stats.time_to_join_response);
                 p2_join_times.push(result.p2_stats.time_to_join_response);
 
+                for stats in [&result.p1_stats, &result.p2_stats] {
+                    bytes_sent += stats.bytes_sent;
+                    bytes_received += stats.bytes_received;
+                    messages_received += stats.messages_received;
+                    codec_time += stats.codec_time;
+                }
+
                 turn_latency_samples.extend(result.p1_stats.turn_latency_samples);
                 turn_latency_samples.extend(result.p2_stats.turn_latency_samples);
             }
@@ -113,6 +169,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let avg = sum.as_millis() / turn_latency_samples.len() as u128;
         println!("mean turn/response latency: {}ms", avg);
     }
+    if messages_received > 0 {
+        println!(
+            "{} encoding: {} bytes sent, {} bytes received in {} messages ({} bytes per message)",
+            args.encoding.param(),
+            bytes_sent,
+            bytes_received,
+            messages_received,
+            bytes_received / messages_received
+        );
+        println!(
+            "mean time encoding and decoding per message received: {}us",
+            codec_time.as_micros() / messages_received as u128
+        );
+    }
 
     Ok(())
 }
@@ -128,7 +198,11 @@ struct GameResult {
 
 type GameID = usize;
 
-async fn play_test_game(id: GameID, address: String) -> Result<GameResult, String> {
+async fn play_test_game(
+    id: GameID,
+    address: String,
+    encoding: Encoding,
+) -> Result<GameResult, String> {
     let start_time = Instant::now();
 
     // let max_connect_retries = 0;
@@ -143,6 +217,7 @@ async fn play_test_game(id: GameID, address: String) -> Result<GameResult, Strin
         // max_connect_retries,
         global_timeout,
         &X_MOVES,
+        encoding,
     )
     .await?;
     // client1 will be dropped (automatic disconnect) if client2 fails now:
@@ -155,6 +230,7 @@ async fn play_test_game(id: GameID, address: String) -> Result<GameResult, Strin
         // max_connect_retries,
         global_timeout,
         &O_MOVES,
+        encoding,
     )
     .await?;
 
@@ -182,7 +258,7 @@ async fn play_test_game(id: GameID, address: String) -> Result<GameResult, Strin
     //     avg
     // );
     Ok(GameResult {
-        overall_time: overall_time,
+        overall_time,
         p1_stats: r1,
         p2_stats: r2,
     })
@@ -218,6 +294,11 @@ struct ClientResult {
     pub time_to_join_response: Duration,
     pub game_time: Duration,
     pub turn_latency_samples: Vec<Duration>,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub messages_received: usize,
+    /// Time spent encoding messages to and decoding messages from the server.
+    pub codec_time: Duration,
 }
 
 impl Drop for Client {
@@ -228,6 +309,7 @@ impl Drop for Client {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn spawn_client(
     game_id: GameID,
     client_id: ClientID,
@@ -237,6 +319,7 @@ async fn spawn_client(
     // max_retries: u64,
     timeout: tokio::time::Duration,
     moves: &'static [usize],
+    encoding: Encoding,
 ) -> Result<Client, String> {
     let (dropped_tx, mut dropped_rx) = oneshot::channel::<bool>();
     let (token_tx, token_rx) = oneshot::channel::<Result<String, String>>();
@@ -244,7 +327,13 @@ async fn spawn_client(
     let (result_tx, result_rx) = oneshot::channel::<Result<ClientResult, String>>();
 
     // TODO: needs proper escaping:
-    let url = format!("{}?token={}&name={}", address, join_token, player_name);
+    let url = format!(
+        "{}?token={}&name={}&encoding={}",
+        address,
+        join_token,
+        player_name,
+        encoding.param()
+    );
 
     tokio::spawn(async move {
         let overall_start_time = Instant::now();
@@ -276,22 +365,44 @@ async fn spawn_client(
         let mut current_move: usize = 0;
         let mut turn_latency_samples: Vec<Duration> = Vec::with_capacity(10);
         let mut time_of_last_request: Option<Instant> = None;
+        let mut bytes_sent: usize = 0;
+        let mut bytes_received: usize = 0;
+        let mut messages_received: usize = 0;
+        let mut codec_time = Duration::ZERO;
         while result.is_none() {
             tokio::select! {
                 msg = conn.next() => {
                     match msg {
                         Some(Ok(msg)) => {
                             match msg {
-                                Message::Text(text) => {
-                                    debug!("{} conn {}: got Text: {}", game_id, client_id, text);
-                                    let parsed: ToBrowser = serde_json::from_str(&text).unwrap();
+                                Message::Text(_) | Message::Binary(_) => {
+                                    debug!("{} conn {}: got {:?}", game_id, client_id, msg);
+                                    bytes_received += msg.len();
+                                    messages_received += 1;
+                                    let decode_start_time = Instant::now();
+                                    let parsed = encoding.decode(&msg.into_data());
+                                    codec_time += decode_start_time.elapsed();
+                                    let parsed = match parsed {
+                                        Ok(parsed) => parsed,
+                                        Err(e) => {
+                                            result = Some(Err(format!("{} conn {}: couldn't decode message: {}", game_id, client_id, e)));
+                                            continue;
+                                        }
+                                    };
                                     match parsed {
-                                        ToBrowser::JoinedGame { token, player_id, state } => {
+                                        ToBrowser::JoinedGame { protocol_version, .. } if protocol_version != PROTOCOL_VERSION => {
+                                            let msg = format!("{} conn {}: server speaks protocol version {}, expected {}", game_id, client_id, protocol_version, PROTOCOL_VERSION);
+                                            if let Some(tx) = token_tx.take() {
+                                                let _ = tx.send(Err(msg.clone()));
+                                            }
+                                            result = Some(Err(msg));
+                                        }
+                                        ToBrowser::JoinedGame { token, player_id, state, .. } => {
                                             time_to_join_response = Some(join_game_start_time.elapsed());
                                             if let Some(tx) = token_tx.take() {
                                                 let _ = tx.send(Ok(token));
                                             }
-                                            let player = state.players.iter().find(|p| p.id == player_id).map(|p| p.clone()).unwrap();
+                                            let player = state.players.iter().find(|p| p.id == player_id).cloned().unwrap();
                                             my_team = player.team;
                                             // state_history.push(state);
                                         },
@@ -317,8 +428,11 @@ async fn spawn_client(
                                                             space: moves[current_move],
                                                         };
                                                         current_move += 1;
-                                                        let msg = serde_json::to_string(&msg).unwrap();
-                                                        conn.send(Message::Text(msg)).await.unwrap();
+                                                        let encode_start_time = Instant::now();
+                                                        let msg = encoding.encode(&msg);
+                                                        codec_time += encode_start_time.elapsed();
+                                                        bytes_sent += msg.len();
+                                                        conn.send(msg).await.unwrap();
                                                         time_of_last_request = Some(Instant::now());
                                                     }
                                                 },
@@ -337,12 +451,11 @@ async fn spawn_client(
 
                                             }
                                         }
-                                        ToBrowser::Error(msg) => {
-                                            result = Some(Err(format!("{} conn {}: got unexpected Error from server: \"{}\"", game_id, client_id, msg)));
+                                        ToBrowser::Error { code, message } => {
+                                            result = Some(Err(format!("{} conn {}: got unexpected {} error from server: \"{}\"", game_id, client_id, code, message)));
                                         }
                                     }
                                 }
-                                Message::Binary(_) => todo!(),
                                 Message::Ping(data) => {
                                     conn.send(Message::Pong(data)).await.unwrap();
                                 }
@@ -379,7 +492,6 @@ async fn spawn_client(
                 // There may be nobody listening on error in some cases, so
                 // ignore failures here.
                 let _ = result_tx.send(Err(msg));
-                return;
             }
             Ok(()) => {
                 // println!("conn {}: exiting", id);
@@ -388,10 +500,14 @@ async fn spawn_client(
                 result_tx
                     .send(Ok(ClientResult {
                         // overall_time: overall_start_time.elapsed(),
-                        time_to_connect: time_to_connect,
+                        time_to_connect,
                         time_to_join_response: time_to_join_response.unwrap(),
                         game_time: join_game_start_time.elapsed(),
-                        turn_latency_samples: turn_latency_samples,
+                        turn_latency_samples,
+                        bytes_sent,
+                        bytes_received,
+                        messages_received,
+                        codec_time,
                     }))
                     .unwrap();
             }
@@ -415,12 +531,12 @@ async fn spawn_client(
                 }
                 Err(_recv_err) => {
                     // we end up here if the other end of the channel got dropped
-                    Err(format!("{} conn {}: connection failed", game_id, client_id).into())
+                    Err(format!("{} conn {}: connection failed", game_id, client_id))
                 }
             }
         }
         _ = sleep(timeout) => {
-            Err(format!("{} conn {}: hit {}ms timeout waiting for token", game_id, client_id, timeout.as_millis()).into())
+            Err(format!("{} conn {}: hit {}ms timeout waiting for token", game_id, client_id, timeout.as_millis()))
         }
     }
 }
@@ -428,12 +544,13 @@ async fn spawn_client(
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 enum ToBrowser {
     JoinedGame {
+        protocol_version: u32,
         token: String,
         player_id: PlayerID,
         state: State,
     },
     GameState(State),
-    Error(String),
+    Error { code: String, message: String },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -479,5 +596,6 @@ struct ChatMessage {
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 enum ChatMessageSource {
     Player(PlayerID),
+    Spectator(PlayerID),
     System,
 }